@@ -1,241 +1,877 @@
 #[cfg(not(target_os = "windows"))]
 use nspt_common::DEFAULT_SOCK_FILE;
 use nspt_common::{
-    calc_transfer_size, get_human_friendly_data_size_str, get_human_friendly_speed_str,
-    NsptNegProtocol, ReadWriteStream, SerializedDataContainer, TestMode, BUF_SIZE, MIN_SEND_BYTES,
-    PROTOCOL_VER, SERVER_PORT_S, TOTAL_SEND_NEG_BYTES,
+    calc_transfer_size, fill_random_bytes, get_human_friendly_data_size_str,
+    get_human_friendly_speed_str, parse_duration, parse_size, percentile, recv_data, recv_expected,
+    recv_greeting, run_timed_round, send_data, send_message, set_speed_format, write_udp_header,
+    Capabilities, ControlStream, Hello, IntervalMeter, IntervalReport, LatencyReport,
+    LatencyTestParams, MessageKind, MeteredStream, Negotiated, NsptError, NsptNegProtocol,
+    OutputFormat, ReadWriteStream, RepeatReport, Role, RoundLimit, RoundReport, RunReport,
+    SessionCookie, SpeedFormat, StreamMeters, TestDirection, TestMode, TestReport, Throughput,
+    ThroughputReport, ThroughputStatistics, TransferReport, UdpTestParams, UnitBase, UnitPrefix,
+    BUF_SIZE, CSV_HEADER, LEGACY_PROTOCOL_VER, MAX_LATENCY_MESSAGE_SIZE, MAX_PARALLEL_STREAMS,
+    MAX_ROUND_DURATION, MAX_UDP_PACKET_SIZE, MIN_SEND_BYTES, ROUND_POLL_INTERVAL, SERVER_PORT_S,
+    TOTAL_SEND_NEG_BYTES, UDP_HEADER_SIZE, UDP_ROUND_DURATION,
 };
-use rand::RngCore;
-use std::net::TcpStream;
+use std::net::{TcpStream, UdpSocket};
 #[cfg(not(target_os = "windows"))]
 use std::os::unix::net::UnixStream;
-use std::{io::prelude::*, io::Write, mem::size_of};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::{fmt, io::prelude::*, io::Write, process, thread};
 use structopt::StructOpt;
 
-fn do_speed_test<T>(server_stream: &mut T, transfer_size: usize) -> usize
+// Human-readable output is suppressed when a machine-readable format is requested.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            print!($($arg)*);
+        }
+    };
+}
+
+macro_rules! sayln {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
+
+fn do_speed_test<T>(
+    test_stream: &mut T,
+    transfer_size: usize,
+    direction: TestDirection,
+    verbose: bool,
+) -> Result<TransferReport, NsptError>
 where
-    T: Read + Write,
+    T: Read + Write + ?Sized,
 {
     let mut buf = [0; BUF_SIZE];
-    let mut rng = rand::thread_rng();
-    for i in 0..BUF_SIZE / size_of::<u64>() {
-        for (j, v) in rng.next_u64().to_le_bytes().iter().enumerate() {
-            buf[i + j] = *v;
-        }
-    }
+    fill_random_bytes(&mut buf);
 
-    println!("Start speed test!");
+    if verbose {
+        sayln!("Start speed test! ({direction})");
+    }
     let prog = transfer_size / BUF_SIZE / 10;
     let mut parcent = 0;
     let mut count = 0;
 
-    let mut next_send_size = BUF_SIZE;
+    let mut next_size = BUF_SIZE;
     let mut stdout = std::io::stdout();
     let mut remain = transfer_size;
 
-    let start = chrono::Local::now();
+    let start = Instant::now();
     while remain > 0 {
-        if count % prog == 0 {
+        if verbose && count % prog == 0 {
             if count > 0 {
-                print!("...");
+                say!("...");
             }
-            print!("{}%", parcent * 10);
+            say!("{}%", parcent * 10);
             let _ = stdout.flush();
             parcent += 1;
         }
 
-        server_stream
-            .write_all(&buf[BUF_SIZE - next_send_size..])
-            .unwrap();
+        match direction {
+            TestDirection::Download => test_stream.read_exact(&mut buf[..next_size])?,
+            _ => test_stream.write_all(&buf[..next_size])?,
+        }
 
-        remain -= next_send_size;
-        next_send_size = BUF_SIZE;
-        if remain < next_send_size {
-            next_send_size = remain;
+        remain -= next_size;
+        next_size = BUF_SIZE;
+        if remain < next_size {
+            next_size = remain;
         }
 
         count += 1;
     }
-    let end = chrono::Local::now();
-    println!();
+    let transfer = TransferReport::new(transfer_size as u64, start.elapsed());
+
+    if verbose {
+        sayln!();
+        sayln!(
+            " -> Finish Data Transfer! speed: {}",
+            get_human_friendly_speed_str(transfer.throughput)
+        );
+    }
+
+    Ok(transfer)
+}
+
+fn print_intervals(intervals: &[IntervalReport], direction: TestDirection) {
+    for interval in intervals {
+        sayln!(
+            "    [{:.2}-{:.2} sec] speed: {}",
+            interval.start_secs,
+            interval.end_secs,
+            interval.throughput.to_speed_str(direction)
+        );
+    }
+}
+
+fn print_by_role(client: &ThroughputReport, server: &ThroughputReport, direction: TestDirection) {
+    let (sender, receiver) = ThroughputReport::by_role(client, server);
+    sayln!("    [sender]   speed: {}", sender.to_speed_str(direction));
+    sayln!("    [receiver] speed: {}", receiver.to_speed_str(direction));
+}
+
+fn print_statistics(statistics: &ThroughputStatistics) {
+    let speed = |bytes_per_second: f64| get_human_friendly_speed_str(Throughput(bytes_per_second));
+    let summaries = [
+        ("upload", statistics.upload),
+        ("download", statistics.download),
+    ];
+
+    for (direction, summary) in summaries {
+        if let Some(x) = summary {
+            sayln!(
+                "statistics ({direction}, {} rounds): min: {}, median: {}, max: {}, stddev: {}, p5: {}, p95: {}, 95% CI: [{}, {}]",
+                x.count,
+                speed(x.min),
+                speed(x.median),
+                speed(x.max),
+                speed(x.stddev),
+                speed(x.p5),
+                speed(x.p95),
+                speed(x.ci95_low),
+                speed(x.ci95_high)
+            );
+        }
+    }
+}
+
+fn do_bidir_speed_test(
+    test_stream: &mut Box<dyn ReadWriteStream + Send>,
+    transfer_size: usize,
+    verbose: bool,
+    meters: &mut StreamMeters,
+) -> Result<ThroughputReport, NsptError> {
+    let mut buf = [0; BUF_SIZE];
+    fill_random_bytes(&mut buf);
+    let mut recv_stream = test_stream.try_clone()?;
+    let StreamMeters { upload, download } = meters;
+
+    if verbose {
+        sayln!("Start speed test! ({})", TestDirection::Bidirectional);
+    }
+    let (sent, received) = thread::scope(|s| {
+        let receiver = s.spawn(move || -> std::io::Result<TransferReport> {
+            let mut recv_buf = [0; BUF_SIZE];
+            let elapsed = recv_data(
+                &mut MeteredStream::new(&mut *recv_stream, download),
+                &mut recv_buf,
+                transfer_size,
+            )?;
+
+            Ok(TransferReport::new(transfer_size as u64, elapsed))
+        });
+
+        let start = Instant::now();
+        let sent = send_data(
+            &mut MeteredStream::new(&mut **test_stream, upload),
+            &buf,
+            transfer_size,
+        )
+        .map(|_| TransferReport::new(transfer_size as u64, start.elapsed()));
+
+        (sent, receiver.join().expect("Receiver thread panicked"))
+    });
+    let throughput = ThroughputReport {
+        upload: Some(sent?),
+        download: Some(received?),
+    };
+
+    if verbose {
+        sayln!(
+            " -> Finish Data Transfer! {}",
+            throughput.to_speed_str(TestDirection::Bidirectional)
+        );
+    }
+
+    Ok(throughput)
+}
+
+fn do_stream_speed_test(
+    test_stream: &mut Box<dyn ReadWriteStream + Send>,
+    transfer_size: usize,
+    direction: TestDirection,
+    verbose: bool,
+    meters: &mut StreamMeters,
+) -> Result<ThroughputReport, NsptError> {
+    match direction {
+        TestDirection::Upload => Ok(ThroughputReport {
+            upload: Some(do_speed_test(
+                &mut MeteredStream::new(&mut **test_stream, &mut meters.upload),
+                transfer_size,
+                direction,
+                verbose,
+            )?),
+            ..Default::default()
+        }),
+        TestDirection::Download => Ok(ThroughputReport {
+            download: Some(do_speed_test(
+                &mut MeteredStream::new(&mut **test_stream, &mut meters.download),
+                transfer_size,
+                direction,
+                verbose,
+            )?),
+            ..Default::default()
+        }),
+        TestDirection::Bidirectional => {
+            do_bidir_speed_test(test_stream, transfer_size, verbose, meters)
+        }
+    }
+}
 
-    let elapse = (end - start).num_milliseconds() as usize; //.to_std().unwrap().as_millis();
-    let bytes_per_ms = transfer_size / elapse;
+fn do_parallel_speed_test(
+    server_stream: &mut ControlStream,
+    test_streams: &mut [Box<dyn ReadWriteStream + Send>],
+    limit: RoundLimit,
+    direction: TestDirection,
+    round: u16,
+    interval: Option<Duration>,
+    round_report: bool,
+) -> Result<RoundReport, NsptError> {
+    let start = Instant::now();
+    let mut meters: Vec<_> = test_streams
+        .iter()
+        .map(|_| StreamMeters::new(start, interval))
+        .collect();
+    // A single stream in byte mode reports its own progress.
+    let verbose = matches!(limit, RoundLimit::Bytes(_)) && test_streams.len() == 1;
+
+    let throughputs = match limit {
+        RoundLimit::Bytes(transfer_size) => {
+            if let [test_stream] = test_streams {
+                vec![do_stream_speed_test(
+                    test_stream,
+                    transfer_size,
+                    direction,
+                    true,
+                    &mut meters[0],
+                )?]
+            } else {
+                sayln!(
+                    "Start speed test! ({direction}, {} streams)",
+                    test_streams.len()
+                );
+                thread::scope(|s| {
+                    let handles: Vec<_> = test_streams
+                        .iter_mut()
+                        .zip(&mut meters)
+                        .map(|(test_stream, meters)| {
+                            s.spawn(move || {
+                                do_stream_speed_test(
+                                    test_stream,
+                                    transfer_size,
+                                    direction,
+                                    false,
+                                    meters,
+                                )
+                            })
+                        })
+                        .collect();
+
+                    handles
+                        .into_iter()
+                        .map(|handle| handle.join().expect("Stream thread panicked"))
+                        .collect::<Result<Vec<_>, _>>()
+                })?
+            }
+        }
+        RoundLimit::Duration(duration) => {
+            sayln!(
+                "Start speed test! ({direction}, {duration:?}, {} streams)",
+                test_streams.len()
+            );
+            run_timed_round(
+                server_stream,
+                test_streams,
+                duration,
+                direction,
+                &mut meters,
+            )?
+        }
+    };
 
-    println!(
-        " -> Finish Data Transfer! speed: {}",
-        get_human_friendly_speed_str(bytes_per_ms)
+    let server_streams = if round_report {
+        recv_expected!(
+            server_stream,
+            NotifyRoundReport(server_streams) if server_streams.len() == throughputs.len() =>
+                server_streams
+        )
+    } else {
+        vec![]
+    };
+    let sum = ThroughputReport::sum(&throughputs);
+    let server_sum = round_report.then(|| ThroughputReport::sum(&server_streams));
+
+    match throughputs[..] {
+        _ if verbose => {}
+        [throughput] => sayln!(
+            " -> Finish Data Transfer! speed: {}",
+            throughput.to_speed_str(direction)
+        ),
+        _ => {
+            for (i, throughput) in throughputs.iter().enumerate() {
+                sayln!(
+                    "    [stream {}] speed: {}",
+                    i + 1,
+                    throughput.to_speed_str(direction)
+                );
+            }
+            sayln!(
+                " -> Finish Data Transfer! [SUM] speed: {}",
+                sum.to_speed_str(direction)
+            );
+        }
+    }
+
+    if let Some(server_sum) = &server_sum {
+        print_by_role(&sum, server_sum, direction);
+    }
+
+    let mut report = RoundReport::new(round, throughputs, sum);
+    report.server_streams = server_streams;
+    report.server_sum = server_sum;
+    report.intervals = IntervalReport::collect(
+        meters
+            .iter()
+            .filter(|_| direction != TestDirection::Download)
+            .map(|x| &x.upload),
+        meters
+            .iter()
+            .filter(|_| direction != TestDirection::Upload)
+            .map(|x| &x.download),
     );
+    print_intervals(&report.intervals, direction);
 
-    bytes_per_ms
+    Ok(report)
 }
 
-fn do_test(
-    mut server_stream: &mut Box<dyn ReadWriteStream + Send>,
+enum ServerAddr {
+    Tcp(String),
+    #[cfg(not(target_os = "windows"))]
+    Unix(String),
+}
+
+impl ServerAddr {
+    fn connect(&self) -> std::io::Result<Box<dyn ReadWriteStream + Send>> {
+        match self {
+            ServerAddr::Tcp(addr) => Ok(Box::new(TcpStream::connect(addr)?)),
+            #[cfg(not(target_os = "windows"))]
+            ServerAddr::Unix(path) => Ok(Box::new(UnixStream::connect(path)?)),
+        }
+    }
+}
+
+impl ServerAddr {
+    fn test_mode(&self) -> TestMode {
+        match self {
+            ServerAddr::Tcp(_) => TestMode::Tcp,
+            #[cfg(not(target_os = "windows"))]
+            ServerAddr::Unix(_) => TestMode::Unix,
+        }
+    }
+}
+
+impl fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerAddr::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(not(target_os = "windows"))]
+            ServerAddr::Unix(path) => write!(f, "{path}"),
+        }
+    }
+}
+
+// Data connections are greeted like the control connection and then name their session.
+fn connect_data_stream(
+    server_addr: &ServerAddr,
+    cookie: SessionCookie,
+) -> Result<Box<dyn ReadWriteStream + Send>, NsptError> {
+    let mut test_stream = server_addr.connect()?;
+    recv_greeting(&mut test_stream)?;
+    send_message(
+        &mut test_stream,
+        &NsptNegProtocol::ClientHello(Hello::new(Capabilities::ALL), Some(cookie)),
+    )?;
+
+    Ok(test_stream)
+}
+
+fn exchange_hello(
+    server_stream: &mut ControlStream,
+    required: Capabilities,
+) -> Result<(SessionCookie, Negotiated), NsptError> {
+    sayln!("Start exchanging Hello message.");
+
+    let local = Hello::new(Capabilities::ALL);
+    let server_ver = server_stream.recv_greeting()?;
+    if server_ver <= LEGACY_PROTOCOL_VER {
+        // A version 1 server waits for our version before it lets go of the connection.
+        let server_hello = Hello::legacy(server_ver);
+        server_stream.send_hello(&NsptNegProtocol::ClientHello(local, None), &server_hello)?;
+        return Err(NsptError::VersionMismatch {
+            local: local.versions(),
+            peer: server_hello.versions(),
+        });
+    }
+
+    server_stream.send(&NsptNegProtocol::ClientHello(local, None))?;
+
+    let (server_hello, cookie) = match server_stream.recv()? {
+        NsptNegProtocol::ServerHello(server_hello, cookie) => (server_hello, cookie),
+        NsptNegProtocol::ServerBusy => return Err(NsptError::ServerBusy),
+        other => return Err(NsptError::unexpected(MessageKind::ServerHello, other)),
+    };
+
+    let negotiated = local.negotiate(&server_hello)?;
+    negotiated.require(required)?;
+
+    sayln!(
+        " -> End exchanging Hello message. (proto-ver: {:#04x}, capabilities: {})",
+        negotiated.version,
+        negotiated.capabilities
+    );
+
+    Ok((cookie, negotiated))
+}
+
+fn do_udp_speed_test(
+    socket: &UdpSocket,
+    params: &UdpTestParams,
+    seq: &mut u64,
+    test_start: Instant,
+    round: u16,
+    interval: Option<Duration>,
+) -> Result<RoundReport, NsptError> {
+    let mut buf = vec![0; params.packet_size];
+    fill_random_bytes(&mut buf);
+
+    let mut sent_bytes: u64 = 0;
+    let mut sent_packets: u64 = 0;
+
+    sayln!("Start speed test! (UDP)");
+    let start = Instant::now();
+    let mut meter = IntervalMeter::new(start, interval);
+    loop {
+        let elapsed = start.elapsed();
+        if elapsed >= params.round_duration {
+            break;
+        }
+
+        let next_due = Duration::from_nanos(
+            (sent_bytes as u128 * 8 * 1_000_000_000 / params.bitrate as u128) as u64,
+        );
+        if next_due > elapsed {
+            thread::sleep((next_due - elapsed).min(params.round_duration - elapsed));
+            continue;
+        }
+
+        write_udp_header(&mut buf, *seq, test_start.elapsed().as_nanos() as u64);
+        socket.send(&buf)?;
+        meter.record(buf.len() as u64);
+        *seq += 1;
+        sent_bytes += buf.len() as u64;
+        sent_packets += 1;
+    }
+
+    let transfer = TransferReport::new(sent_bytes, start.elapsed());
+    sayln!(
+        " -> Finish Data Transfer! sent: {sent_packets} packets, speed: {}",
+        get_human_friendly_speed_str(transfer.throughput)
+    );
+
+    let throughput = ThroughputReport {
+        upload: Some(transfer),
+        ..Default::default()
+    };
+    let mut report = RoundReport::new(round, vec![throughput], throughput);
+    report.intervals = IntervalReport::collect([&meter], []);
+    print_intervals(&report.intervals, TestDirection::Upload);
+
+    Ok(report)
+}
+
+fn do_udp_test(
+    server_addr: &ServerAddr,
+    server_stream: &mut ControlStream,
+    server_ip: &str,
+    params: UdpTestParams,
+    interval: Option<Duration>,
+) -> Result<TestReport, NsptError> {
+    let UdpTestParams {
+        bitrate,
+        packet_size,
+        test_times,
+        ..
+    } = params;
+
+    let (_, negotiated) = exchange_hello(server_stream, Capabilities::UDP)?;
+
+    server_stream.send(&NsptNegProtocol::NotifyUdpTest(params))?;
+
+    let udp_port = recv_expected!(server_stream, NotifyUdpPort(udp_port) => udp_port);
+
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect((server_ip, udp_port))?;
+
+    sayln!(
+        "[Condition] bitrate: {bitrate} b/s, packet_size: {packet_size}, test_times: {test_times}"
+    );
+
+    let mut report = TestReport::new(TestMode::Udp, server_addr.to_string());
+    report.protocol_version = negotiated.version;
+    report.direction = Some(TestDirection::Upload);
+    report.test_times = test_times;
+    report.round_duration_secs = Some(params.round_duration.as_secs_f64());
+
+    let mut seq = 0;
+    let test_start = Instant::now();
+    for round in 0..test_times {
+        report.rounds.push(do_udp_speed_test(
+            &socket,
+            &params,
+            &mut seq,
+            test_start,
+            round + 1,
+            interval,
+        )?);
+    }
+
+    server_stream.send(&NsptNegProtocol::EndOfUdpTransfer(seq))?;
+
+    let stats = recv_expected!(server_stream, UdpTestReport(stats) => stats);
+
+    server_stream.expect(MessageKind::EndOfSpeedTest)?;
+
+    sayln!(
+        "sent: {seq}, received: {}, lost: {} ({:.2}%), duplicated: {}, out-of-order: {}, jitter: {:.3} ms",
+        stats.packets_received,
+        stats.packets_lost,
+        stats.loss_percent(),
+        stats.packets_duplicated,
+        stats.packets_reordered,
+        stats.jitter_ns as f64 / 1_000_000.
+    );
+
+    let statistics = ThroughputStatistics::new(report.rounds.iter().map(|x| &x.sum));
+    print_statistics(&statistics);
+
+    report.average = Some(ThroughputReport::average(
+        report.rounds.iter().map(|x| &x.sum),
+    ));
+    report.statistics = Some(statistics);
+    report.udp = Some(stats);
+
+    Ok(report)
+}
+
+fn do_latency_test(
+    server_addr: &ServerAddr,
+    server_stream: &mut ControlStream,
+    message_size: usize,
+    transactions: u32,
+) -> Result<TestReport, NsptError> {
+    let (cookie, negotiated) = exchange_hello(server_stream, Capabilities::LATENCY)?;
+
+    server_stream.send(&NsptNegProtocol::NotifyLatencyTest(LatencyTestParams {
+        message_size,
+        transactions,
+    }))?;
+
+    let test_stream = &mut connect_data_stream(server_addr, cookie)?;
+    test_stream.set_nodelay(true)?;
+    sayln!("Data connection is Established!");
+
+    server_stream.expect(MessageKind::StartSpeedTest)?;
+
+    sayln!("[Condition] message_size: {message_size}, transactions: {transactions}");
+    sayln!("Start latency test!");
+
+    let mut buf = vec![0; message_size];
+    fill_random_bytes(&mut buf);
+    let mut rtts = Vec::with_capacity(transactions as usize);
+
+    let start = Instant::now();
+    for _ in 0..transactions {
+        let sent = Instant::now();
+        test_stream.write_all(&buf)?;
+        test_stream.read_exact(&mut buf)?;
+        rtts.push(sent.elapsed());
+    }
+    let elapsed = start.elapsed();
+
+    server_stream.send(&NsptNegProtocol::EndOfTransfer)?;
+
+    server_stream.expect(MessageKind::EndOfSpeedTest)?;
+
+    rtts.sort();
+    let rtts_ns: Vec<_> = rtts.iter().map(|x| x.as_nanos() as f64).collect();
+    let latency = LatencyReport {
+        message_size,
+        transactions,
+        min_ns: rtts[0].as_nanos() as u64,
+        avg_ns: (rtts.iter().sum::<Duration>() / transactions).as_nanos() as u64,
+        max_ns: rtts[rtts.len() - 1].as_nanos() as u64,
+        p50_ns: percentile(&rtts_ns, 50.) as u64,
+        p99_ns: percentile(&rtts_ns, 99.) as u64,
+        transactions_per_second: transactions as f64 / elapsed.as_secs_f64(),
+    };
+    sayln!(
+        "rtt min/avg/max/p50/p99: {:?}/{:?}/{:?}/{:?}/{:?}",
+        Duration::from_nanos(latency.min_ns),
+        Duration::from_nanos(latency.avg_ns),
+        Duration::from_nanos(latency.max_ns),
+        Duration::from_nanos(latency.p50_ns),
+        Duration::from_nanos(latency.p99_ns)
+    );
+    sayln!(
+        "transactions per second: {:.2}",
+        latency.transactions_per_second
+    );
+
+    let mut report = TestReport::new(server_addr.test_mode(), server_addr.to_string());
+    report.protocol_version = negotiated.version;
+    report.latency = Some(latency);
+
+    Ok(report)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct StreamTestOptions {
     test_times: u16,
     transfer_bytes: Option<usize>,
-) {
-    println!("Start exchanging Hello message.");
-    {
-        // Exchange Hello
-        let (version_matched, server_proto_ver) =
-            if let NsptNegProtocol::ServerHello(server_proto_ver) =
-                SerializedDataContainer::from_reader(&mut server_stream)
-                    .unwrap()
-                    .to_serializable_data::<NsptNegProtocol>()
-                    .unwrap()
-            {
-                (server_proto_ver == PROTOCOL_VER, server_proto_ver)
-            } else {
-                panic!("Protocol err")
-            };
+    duration: Option<Duration>,
+    direction: TestDirection,
+    parallel: u16,
+    interval: Option<Duration>,
+    warmup: u16,
+    adaptive: Option<AdaptiveStop>,
+}
+
+// Stop once the relative standard error of the round throughput is at most `max_rse`.
+#[derive(Debug, Clone, Copy)]
+struct AdaptiveStop {
+    max_rse: f64,
+    max_rounds: u16,
+}
+
+const MIN_ADAPTIVE_ROUNDS: u16 = 3;
+
+fn do_test(
+    server_addr: &ServerAddr,
+    server_stream: &mut ControlStream,
+    options: &StreamTestOptions,
+) -> Result<TestReport, NsptError> {
+    let StreamTestOptions {
+        test_times,
+        transfer_bytes,
+        duration,
+        direction,
+        parallel,
+        interval,
+        warmup,
+        adaptive,
+    } = *options;
+
+    let required = [
+        (direction == TestDirection::Download, Capabilities::DOWNLOAD),
+        (
+            direction == TestDirection::Bidirectional,
+            Capabilities::BIDIRECTIONAL,
+        ),
+        (parallel > 1, Capabilities::PARALLEL),
+        (duration.is_some(), Capabilities::TIMED_ROUNDS),
+        (adaptive.is_some(), Capabilities::OPEN_ENDED_ROUNDS),
+    ]
+    .into_iter()
+    .filter(|(needed, _)| *needed)
+    .fold(Capabilities::NONE, |acc, (_, capability)| acc | capability);
+    let (cookie, negotiated) = exchange_hello(server_stream, required)?;
+    // Without the server's measurements only the client side of each round is reported.
+    let round_report = negotiated.capabilities.contains(Capabilities::ROUND_REPORT);
+
+    server_stream.send(&NsptNegProtocol::NotifyStreamCount(parallel))?;
 
-        server_stream
-            .write_all(
-                &SerializedDataContainer::from_serializable_data(&NsptNegProtocol::ClientHello(
-                    PROTOCOL_VER,
-                ))
-                .unwrap()
-                .to_one_vec(),
-            )
-            .expect("Failed to send ClientHello");
-        if !version_matched {
-            panic!("Protocol version mismatched! this proto-ver: {PROTOCOL_VER} but server proto-ver: {server_proto_ver}");
-        }
-    }
-    println!(" -> End exchanging Hello message.");
-
-    let transfer_size = if let Some(transfer_bytes) = transfer_bytes {
-        server_stream
-            .write_all(
-                &SerializedDataContainer::from_serializable_data(
-                    &NsptNegProtocol::SpeedNegotiation(false),
-                )
-                .unwrap()
-                .to_one_vec(),
-            )
-            .expect("Failed to send SpeedNegotiation");
-
-        transfer_bytes
+    let mut test_streams = Vec::with_capacity(parallel as usize);
+    for _ in 0..parallel {
+        test_streams.push(connect_data_stream(server_addr, cookie)?);
+    }
+    sayln!("Data connection is Established! ({parallel} streams)");
+
+    server_stream.send(&NsptNegProtocol::NotifyTestDirection(direction))?;
+
+    let limit = if let Some(duration) = duration {
+        server_stream.send(&NsptNegProtocol::SpeedNegotiation(false))?;
+
+        // Receivers poll for the byte count reported by EndOfRound.
+        for test_stream in &test_streams {
+            test_stream.set_read_timeout(Some(ROUND_POLL_INTERVAL))?;
+        }
+
+        RoundLimit::Duration(duration)
+    } else if let Some(transfer_bytes) = transfer_bytes {
+        server_stream.send(&NsptNegProtocol::SpeedNegotiation(false))?;
+
+        RoundLimit::Bytes(transfer_bytes)
     } else {
         // Determin amount of transfer size
         let mut neg_test_buf = [0; BUF_SIZE];
-        let mut rng = rand::thread_rng();
-        for i in 0..BUF_SIZE / size_of::<u32>() {
-            let bytes: &[u8; 4] = &rng.next_u32().to_le_bytes();
-            neg_test_buf[i] = bytes[0];
-            neg_test_buf[i + 1] = bytes[1];
-            neg_test_buf[i + 2] = bytes[2];
-            neg_test_buf[i + 3] = bytes[3];
-        }
-
-        server_stream
-            .write_all(
-                &SerializedDataContainer::from_serializable_data(
-                    &NsptNegProtocol::SpeedNegotiation(true),
-                )
-                .unwrap()
-                .to_one_vec(),
-            )
-            .expect("Failed to send SpeedNegotiation");
-
-        server_stream
-            .write_all(
-                &SerializedDataContainer::from_serializable_data(
-                    &NsptNegProtocol::StartSpeedNegotiation,
-                )
-                .unwrap()
-                .to_one_vec(),
-            )
-            .expect("Failed to send StartSpeedNegotiation");
-
-        if let NsptNegProtocol::StartSpeedNegotiation =
-            SerializedDataContainer::from_reader(&mut server_stream)
-                .unwrap()
-                .to_serializable_data::<NsptNegProtocol>()
-                .unwrap()
-        {
-            let mut total: usize = 0;
+        fill_random_bytes(&mut neg_test_buf);
 
-            println!("Start small speed test for negotiation...");
-            let start = chrono::Local::now();
+        server_stream.send(&NsptNegProtocol::SpeedNegotiation(true))?;
+        server_stream.send(&NsptNegProtocol::StartSpeedNegotiation)?;
 
-            while total < TOTAL_SEND_NEG_BYTES {
-                server_stream.write_all(&neg_test_buf).unwrap();
-                total += BUF_SIZE;
-            }
-            let end = chrono::Local::now();
+        server_stream.expect(MessageKind::StartSpeedNegotiation)?;
 
-            println!(" -> End of data transfer...");
+        let test_stream = &mut test_streams[0];
+        let mut total: usize = 0;
 
-            let elapse = (end - start).num_milliseconds();
-            let bytes_per_ms = TOTAL_SEND_NEG_BYTES as f64 / elapse as f64;
+        sayln!("Start small speed test for negotiation...");
+        let start = Instant::now();
 
-            calc_transfer_size(bytes_per_ms)
-        } else {
-            panic!("Protocol err")
+        while total < TOTAL_SEND_NEG_BYTES {
+            match direction {
+                TestDirection::Download => test_stream.read_exact(&mut neg_test_buf)?,
+                _ => test_stream.write_all(&neg_test_buf)?,
+            }
+            total += BUF_SIZE;
         }
+        let elapsed = start.elapsed();
+
+        sayln!(" -> End of data transfer...");
+
+        RoundLimit::Bytes(calc_transfer_size(Throughput::new(total as u64, elapsed)))
     };
 
-    println!(
-        "[Condition] transfer_size: {}({transfer_size}), test_times: {test_times}",
-        get_human_friendly_data_size_str(transfer_size as u64)
+    let rounds = match adaptive {
+        Some(adaptive) => format!(
+            "until rse <= {:.2}% (max {})",
+            adaptive.max_rse * 100.,
+            adaptive.max_rounds
+        ),
+        None => test_times.to_string(),
+    };
+    sayln!(
+        "[Condition] round: {limit}, test_times: {rounds}, warmup: {warmup}, direction: {direction}, streams: {parallel}"
     );
 
-    let total = {
-        server_stream
-            .write_all(
-                &SerializedDataContainer::from_serializable_data(
-                    &NsptNegProtocol::NotifyBufferSize(transfer_size, test_times),
-                )
-                .unwrap()
-                .to_one_vec(),
-            )
-            .expect("Failed to send NotifyBufferSize");
-
-        if let NsptNegProtocol::StartSpeedTest =
-            SerializedDataContainer::from_reader(&mut server_stream)
-                .unwrap()
-                .to_serializable_data::<NsptNegProtocol>()
-                .unwrap()
-        {
-            let mut total = 0;
+    let mut report = TestReport::new(server_addr.test_mode(), server_addr.to_string());
+    report.protocol_version = negotiated.version;
+    report.direction = Some(direction);
+    report.test_times = test_times;
+    report.warmup = warmup;
+    report.parallel = parallel;
+    match limit {
+        RoundLimit::Bytes(transfer_size) => report.transfer_size = Some(transfer_size),
+        RoundLimit::Duration(duration) => report.round_duration_secs = Some(duration.as_secs_f64()),
+    }
+
+    {
+        // The server runs warm-up rounds like any other, so it is told the total count.
+        let total_rounds = match adaptive {
+            Some(_) => None,
+            None => Some(test_times + warmup),
+        };
+        match limit {
+            RoundLimit::Bytes(transfer_size) => server_stream.send(
+                &NsptNegProtocol::NotifyBufferSize(transfer_size, total_rounds),
+            )?,
+            RoundLimit::Duration(duration) => server_stream.send(
+                &NsptNegProtocol::NotifyRoundDuration(duration, total_rounds),
+            )?,
+        }
+
+        server_stream.expect(MessageKind::StartSpeedTest)?;
 
-            for _ in 0..test_times {
-                total += do_speed_test(&mut server_stream, transfer_size);
+        // Timed rounds wait until the client has drained the previous one, and open-ended
+        // rounds need to be announced anyway.
+        let announced = adaptive.is_some() || matches!(limit, RoundLimit::Duration(_));
+        let mut round: u16 = 0;
+        loop {
+            let warming_up = round < warmup;
+            let measured = report.rounds.len() as u16;
+            let finished = match adaptive {
+                None => measured >= test_times,
+                Some(adaptive) => {
+                    let converged = report
+                        .statistics
+                        .and_then(|x| x.max_relative_standard_error())
+                        .is_some_and(|rse| rse <= adaptive.max_rse);
+                    measured >= adaptive.max_rounds
+                        || (measured >= MIN_ADAPTIVE_ROUNDS && converged)
+                }
+            };
+            if !warming_up && finished {
+                break;
             }
 
-            server_stream
-                .write_all(
-                    &SerializedDataContainer::from_serializable_data(
-                        &NsptNegProtocol::EndOfTransfer,
-                    )
-                    .unwrap()
-                    .to_one_vec(),
-                )
-                .expect("Failed to send EndOfTransfer");
+            if warming_up {
+                sayln!("[Warm-up round {}/{warmup}]", round + 1);
+            }
+            if announced {
+                server_stream.send(&NsptNegProtocol::StartRound)?;
+            }
+
+            let round_report = do_parallel_speed_test(
+                server_stream,
+                &mut test_streams,
+                limit,
+                direction,
+                if warming_up { round } else { measured } + 1,
+                interval,
+                round_report,
+            )?;
+            round += 1;
+
+            if warming_up {
+                report.warmup_rounds.push(round_report);
+                continue;
+            }
+            report.rounds.push(round_report);
 
-            total
-        } else {
-            panic!("Protocol err")
+            if let Some(adaptive) = adaptive {
+                let statistics = ThroughputStatistics::new(report.rounds.iter().map(|x| &x.sum));
+                if let Some(rse) = statistics.max_relative_standard_error() {
+                    sayln!(
+                        "    relative standard error: {:.2}% (target: {:.2}%)",
+                        rse * 100.,
+                        adaptive.max_rse * 100.
+                    );
+                }
+                report.statistics = Some(statistics);
+            }
         }
-    };
+        report.test_times = report.rounds.len() as u16;
+
+        server_stream.send(&NsptNegProtocol::EndOfTransfer)?;
+    }
 
     {
-        if let NsptNegProtocol::EndOfSpeedTest =
-            SerializedDataContainer::from_reader(&mut server_stream)
-                .unwrap()
-                .to_serializable_data::<NsptNegProtocol>()
-                .unwrap()
-        {
-            println!(
-                "average: {}",
-                get_human_friendly_speed_str(total / (test_times as usize))
-            );
-        } else {
-            panic!("Protocol err")
+        server_stream.expect(MessageKind::EndOfSpeedTest)?;
+
+        let average = ThroughputReport::average(report.rounds.iter().map(|x| &x.sum));
+
+        let server_average = round_report
+            .then(|| ThroughputReport::average(report.rounds.iter().flat_map(|x| &x.server_sum)));
+
+        sayln!("average: {}", average.to_speed_str(direction));
+        if let Some(server_average) = &server_average {
+            print_by_role(&average, server_average, direction);
         }
+        let statistics = ThroughputStatistics::new(report.rounds.iter().map(|x| &x.sum));
+        print_statistics(&statistics);
+
+        report.average = Some(average);
+        report.server_average = server_average;
+        report.statistics = Some(statistics);
     }
+
+    Ok(report)
 }
 
 const DEFAULT_SERVER_IP: &str = "127.0.0.1";
@@ -254,45 +890,355 @@ struct NsptClientArg {
     test_mode: TestMode,
     #[structopt(short, long, default_value = "10")]
     test_times: u16,
-    #[structopt(short = "d", long)]
+    /// Bytes to transfer per round (e.g. 24M, 1G, 512KiB)
+    #[structopt(short = "d", long, parse(try_from_str = parse_size))]
     transfer_bytes: Option<usize>,
+    /// Run each round for a fixed time (e.g. 10, 1.5s, 500ms) instead of a byte count
+    #[structopt(short = "T", long, parse(try_from_str = parse_duration), conflicts_with_all = &["transfer-bytes", "latency"])]
+    duration: Option<Duration>,
+    /// Run the test in reverse mode (server sends, client receives)
+    #[structopt(short = "R", long)]
+    reverse: bool,
+    /// Send and receive at the same time
+    #[structopt(long, conflicts_with = "reverse")]
+    bidir: bool,
+    /// Number of parallel data streams
+    #[structopt(short = "P", long, default_value = "1")]
+    parallel: u16,
+    /// Target bitrate in bits/sec for UDP mode
+    #[structopt(short = "b", long, default_value = "1000000")]
+    bitrate: u64,
+    /// Datagram size in bytes for UDP mode
+    #[structopt(short = "l", long, default_value = "1470", parse(try_from_str = parse_size))]
+    packet_size: usize,
+    /// Measure request/response latency instead of throughput
+    #[structopt(long, conflicts_with_all = &["reverse", "bidir"])]
+    latency: bool,
+    /// Message size in bytes for latency mode
+    #[structopt(long, default_value = "1", parse(try_from_str = parse_size))]
+    message_size: usize,
+    /// Number of request/response transactions for latency mode
+    #[structopt(long, default_value = "10000")]
+    transactions: u32,
+    /// Output format: text, json or csv (one row per round)
+    #[structopt(short = "f", long, default_value = "text", parse(try_from_str))]
+    format: OutputFormat,
+    /// Shorthand for --format json
+    #[structopt(long, conflicts_with = "format")]
+    json: bool,
+    /// Print the CSV header line before the rows
+    #[structopt(long)]
+    csv_header: bool,
+    /// Speed unit base: si (1000) or iec (1024)
+    #[structopt(long, default_value = "si", parse(try_from_str))]
+    units: UnitBase,
+    /// Report speeds in bytes/sec instead of bits/sec
+    #[structopt(long)]
+    bytes: bool,
+    /// Number of decimal places in reported speeds
+    #[structopt(long, default_value = "2")]
+    precision: usize,
+    /// Always report speeds with this unit prefix (1, K, M, G or T)
+    #[structopt(long, parse(try_from_str))]
+    format_unit: Option<UnitPrefix>,
+    /// Run N extra rounds first and leave them out of the results
+    #[structopt(long, default_value = "0")]
+    warmup: u16,
+    /// Keep running rounds until the relative standard error (in %) drops to this value
+    #[structopt(long, conflicts_with = "latency")]
+    target_rse: Option<f64>,
+    /// Upper bound on the number of rounds with --target-rse
+    #[structopt(long, default_value = "100")]
+    max_rounds: u16,
+    /// Report throughput for every time slice of this many milliseconds within a round
+    #[structopt(long)]
+    interval_ms: Option<u64>,
+    /// Run the whole test N times, each run on its own connection
+    #[structopt(long, default_value = "1")]
+    repeat: u32,
+    /// Wait between repeated runs (e.g. 10, 1.5s, 500ms)
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    interval: Option<Duration>,
 }
 
 fn main() {
     let nspt_client_arg = NsptClientArg::from_args();
+    let format = if nspt_client_arg.json {
+        OutputFormat::Json
+    } else {
+        nspt_client_arg.format
+    };
+    QUIET.store(format != OutputFormat::Text, Ordering::Relaxed);
+    set_speed_format(SpeedFormat {
+        base: nspt_client_arg.units,
+        bytes: nspt_client_arg.bytes,
+        precision: nspt_client_arg.precision,
+        unit: nspt_client_arg.format_unit,
+    });
 
     if let Some(transfer_bytes) = nspt_client_arg.transfer_bytes {
         if transfer_bytes < MIN_SEND_BYTES {
-            panic!("{transfer_bytes} bytes ({}) are too smal to test. min value of it is: {MIN_SEND_BYTES}({})", 
-                   get_human_friendly_data_size_str(transfer_bytes as u64),
-                   get_human_friendly_data_size_str(MIN_SEND_BYTES as u64));
+            eprintln!(
+                "{transfer_bytes} bytes ({}) are too small to test. min value of it is: {MIN_SEND_BYTES} ({})",
+                get_human_friendly_data_size_str(transfer_bytes as u64).trim(),
+                get_human_friendly_data_size_str(MIN_SEND_BYTES as u64).trim()
+            );
+            process::exit(1);
         }
     }
 
-    let (mut server_stream, server_addr): (Box<dyn ReadWriteStream + Send>, String) =
-        match nspt_client_arg.test_mode {
-            TestMode::Tcp => {
-                let server_addr = format!(
-                    "{}:{}",
-                    nspt_client_arg.server_ip, nspt_client_arg.server_port
-                );
-                (
-                    Box::new(TcpStream::connect(&server_addr).unwrap()),
-                    server_addr,
-                )
-            }
-            #[cfg(not(target_os = "windows"))]
-            TestMode::Unix => (
-                Box::new(UnixStream::connect(&nspt_client_arg.server_sock).unwrap()),
-                nspt_client_arg.server_sock,
+    if let Some(duration) = nspt_client_arg.duration {
+        if duration > MAX_ROUND_DURATION {
+            eprintln!("Round duration must not exceed {MAX_ROUND_DURATION:?}");
+            process::exit(1);
+        }
+    }
+
+    if nspt_client_arg.test_times == 0 {
+        eprintln!("Number of test times must be greater than 0");
+        process::exit(1);
+    }
+
+    if nspt_client_arg.parallel == 0 || nspt_client_arg.parallel > MAX_PARALLEL_STREAMS {
+        eprintln!("Number of parallel streams must be between 1 and {MAX_PARALLEL_STREAMS}");
+        process::exit(1);
+    }
+
+    if let TestMode::Udp = nspt_client_arg.test_mode {
+        if nspt_client_arg.reverse || nspt_client_arg.bidir {
+            eprintln!("Only the upload direction is supported over UDP");
+            process::exit(1);
+        }
+
+        if nspt_client_arg.parallel > 1 {
+            eprintln!("Parallel streams are not supported over UDP");
+            process::exit(1);
+        }
+    }
+
+    if nspt_client_arg.warmup > 0 {
+        if nspt_client_arg.latency {
+            eprintln!("Warm-up rounds are not supported in latency mode");
+            process::exit(1);
+        }
+
+        if let TestMode::Udp = nspt_client_arg.test_mode {
+            eprintln!("Warm-up rounds are not supported over UDP");
+            process::exit(1);
+        }
+
+        if nspt_client_arg
+            .test_times
+            .checked_add(nspt_client_arg.warmup)
+            .is_none()
+        {
+            eprintln!(
+                "Test times plus warm-up rounds must not exceed {}",
+                u16::MAX
+            );
+            process::exit(1);
+        }
+    }
+
+    if let Some(target_rse) = nspt_client_arg.target_rse {
+        if let TestMode::Udp = nspt_client_arg.test_mode {
+            eprintln!("Adaptive stopping is not supported over UDP");
+            process::exit(1);
+        }
+
+        if !(target_rse > 0. && target_rse.is_finite()) {
+            eprintln!("Target relative standard error must be a positive percentage");
+            process::exit(1);
+        }
+
+        if nspt_client_arg.max_rounds < MIN_ADAPTIVE_ROUNDS {
+            eprintln!("Max rounds must be at least {MIN_ADAPTIVE_ROUNDS}");
+            process::exit(1);
+        }
+    }
+
+    if nspt_client_arg.interval_ms == Some(0) {
+        eprintln!("Report interval must be greater than 0");
+        process::exit(1);
+    }
+
+    if nspt_client_arg.repeat == 0 {
+        eprintln!("Number of repeats must be greater than 0");
+        process::exit(1);
+    }
+
+    if nspt_client_arg.bitrate == 0 {
+        eprintln!("Bitrate must be greater than 0");
+        process::exit(1);
+    }
+
+    if nspt_client_arg.packet_size < UDP_HEADER_SIZE
+        || nspt_client_arg.packet_size > MAX_UDP_PACKET_SIZE
+    {
+        eprintln!("Packet size must be between {UDP_HEADER_SIZE} and {MAX_UDP_PACKET_SIZE}");
+        process::exit(1);
+    }
+
+    if nspt_client_arg.latency {
+        if let TestMode::Udp = nspt_client_arg.test_mode {
+            eprintln!("Latency mode is not supported over UDP");
+            process::exit(1);
+        }
+
+        if nspt_client_arg.message_size == 0
+            || nspt_client_arg.message_size > MAX_LATENCY_MESSAGE_SIZE
+        {
+            eprintln!("Message size must be between 1 and {MAX_LATENCY_MESSAGE_SIZE}");
+            process::exit(1);
+        }
+
+        if nspt_client_arg.transactions == 0 {
+            eprintln!("Number of transactions must be greater than 0");
+            process::exit(1);
+        }
+
+        // CSV rows are per round, and a latency test has none.
+        if format == OutputFormat::Csv {
+            eprintln!("CSV output is not supported in latency mode");
+            process::exit(1);
+        }
+    }
+
+    let server_addr = match nspt_client_arg.test_mode {
+        TestMode::Tcp | TestMode::Udp => ServerAddr::Tcp(format!(
+            "{}:{}",
+            nspt_client_arg.server_ip, nspt_client_arg.server_port
+        )),
+        #[cfg(not(target_os = "windows"))]
+        TestMode::Unix => ServerAddr::Unix(nspt_client_arg.server_sock),
+    };
+    sayln!("Server addr is: {server_addr}");
+
+    let direction = if nspt_client_arg.bidir {
+        TestDirection::Bidirectional
+    } else if nspt_client_arg.reverse {
+        TestDirection::Download
+    } else {
+        TestDirection::Upload
+    };
+    let report_interval = nspt_client_arg.interval_ms.map(Duration::from_millis);
+    let run_test = || {
+        let server_stream = &mut ControlStream::new(server_addr.connect()?, Role::Client);
+        sayln!("Connection is Established!");
+
+        let result = match nspt_client_arg.test_mode {
+            _ if nspt_client_arg.latency => do_latency_test(
+                &server_addr,
+                server_stream,
+                nspt_client_arg.message_size,
+                nspt_client_arg.transactions,
+            ),
+            TestMode::Udp => do_udp_test(
+                &server_addr,
+                server_stream,
+                &nspt_client_arg.server_ip,
+                UdpTestParams {
+                    bitrate: nspt_client_arg.bitrate,
+                    packet_size: nspt_client_arg.packet_size,
+                    round_duration: nspt_client_arg.duration.unwrap_or(UDP_ROUND_DURATION),
+                    test_times: nspt_client_arg.test_times,
+                },
+                report_interval,
+            ),
+            _ => do_test(
+                &server_addr,
+                server_stream,
+                &StreamTestOptions {
+                    test_times: nspt_client_arg.test_times,
+                    transfer_bytes: nspt_client_arg.transfer_bytes,
+                    duration: nspt_client_arg.duration,
+                    direction,
+                    parallel: nspt_client_arg.parallel,
+                    interval: report_interval,
+                    warmup: nspt_client_arg.warmup,
+                    adaptive: nspt_client_arg.target_rse.map(|target_rse| AdaptiveStop {
+                        max_rse: target_rse / 100.,
+                        max_rounds: nspt_client_arg.max_rounds,
+                    }),
+                },
             ),
         };
-    println!("Server addr is: {server_addr}");
+        // Tell the server why the test ends instead of just dropping the connection.
+        if let Err(e) = &result {
+            server_stream.report_error(e);
+        }
 
-    println!("Connection is Established!");
-    do_test(
-        &mut server_stream,
-        nspt_client_arg.test_times,
-        nspt_client_arg.transfer_bytes,
-    );
+        result
+    };
+
+    if format == OutputFormat::Csv && nspt_client_arg.csv_header {
+        println!("{CSV_HEADER}");
+    }
+
+    let repeat = nspt_client_arg.repeat;
+    let mut runs = Vec::with_capacity(repeat as usize);
+    for run in 1..=repeat {
+        if run > 1 {
+            if let Some(interval) = nspt_client_arg.interval {
+                thread::sleep(interval);
+            }
+        }
+        if repeat > 1 {
+            sayln!("[Run {run}/{repeat}]");
+        }
+
+        // A failed run is recorded and the remaining runs still go ahead.
+        let run_report = match run_test() {
+            Ok(report) => {
+                if format == OutputFormat::Csv {
+                    print!("{}", report.to_csv());
+                }
+                RunReport {
+                    run,
+                    report: Some(report),
+                    error: None,
+                }
+            }
+            Err(e) => {
+                eprintln!("Test failed: {e}");
+                RunReport {
+                    run,
+                    report: None,
+                    error: Some(e.to_string()),
+                }
+            }
+        };
+        runs.push(run_report);
+    }
+
+    let summary = RepeatReport::new(runs);
+
+    if repeat > 1 {
+        sayln!(
+            "[Summary] runs: {repeat}, succeeded: {}, failed: {}",
+            summary.succeeded,
+            summary.failed
+        );
+        if let Some(average) = summary.average {
+            sayln!("average of all runs: {}", average.to_speed_str(direction));
+        }
+        if let Some(statistics) = &summary.statistics {
+            print_statistics(statistics);
+        }
+    }
+
+    if format == OutputFormat::Json {
+        match &summary.runs[..] {
+            [RunReport {
+                report: Some(report),
+                ..
+            }] => println!("{}", report.to_json()),
+            [run] => println!("{}", run.to_json()),
+            _ => println!("{}", summary.to_json()),
+        }
+    }
+
+    if summary.succeeded == 0 {
+        process::exit(1);
+    }
 }
@@ -0,0 +1,183 @@
+use crate::{Capabilities, MessageKind, NsptNegProtocol, ProtocolState, ProtocolVer, Role};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::ErrorKind;
+use std::ops::RangeInclusive;
+
+// Reason carried by NsptNegProtocol::Error. Codes unknown to us still decode, so newer
+// peers can add more.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ErrorCode(pub u16);
+
+impl ErrorCode {
+    pub const INTERNAL: Self = Self(1);
+    pub const MALFORMED_MESSAGE: Self = Self(2);
+    pub const UNEXPECTED_MESSAGE: Self = Self(3);
+    pub const VERSION_MISMATCH: Self = Self(4);
+    pub const UNSUPPORTED: Self = Self(5);
+    pub const INVALID_PARAMETER: Self = Self(6);
+    pub const TIMEOUT: Self = Self(7);
+
+    const NAMES: [(Self, &'static str); 7] = [
+        (Self::INTERNAL, "internal"),
+        (Self::MALFORMED_MESSAGE, "malformed message"),
+        (Self::UNEXPECTED_MESSAGE, "unexpected message"),
+        (Self::VERSION_MISMATCH, "version mismatch"),
+        (Self::UNSUPPORTED, "unsupported"),
+        (Self::INVALID_PARAMETER, "invalid parameter"),
+        (Self::TIMEOUT, "timeout"),
+    ];
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match Self::NAMES.iter().find(|(code, _)| code == self) {
+            Some((_, name)) => write!(f, "{name}"),
+            None => write!(f, "code {}", self.0),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum NsptError {
+    Io(std::io::Error),
+    Encode,
+    Decode,
+    UnexpectedMessage {
+        expected: MessageKind,
+        actual: Box<NsptNegProtocol>,
+    },
+    VersionMismatch {
+        local: RangeInclusive<ProtocolVer>,
+        peer: RangeInclusive<ProtocolVer>,
+    },
+    Unsupported(Capabilities),
+    Timeout,
+    ServerBusy,
+    InvalidParameter(String),
+    FrameTooLarge {
+        size: usize,
+        max: usize,
+    },
+    TruncatedFrame {
+        expected: usize,
+        actual: usize,
+    },
+    PeerError {
+        code: ErrorCode,
+        message: String,
+    },
+    PeerAborted,
+    OutOfOrder {
+        state: ProtocolState,
+        sender: Role,
+        message: MessageKind,
+    },
+}
+
+impl NsptError {
+    pub fn unexpected(expected: MessageKind, actual: NsptNegProtocol) -> Self {
+        NsptError::UnexpectedMessage {
+            expected,
+            actual: Box::new(actual),
+        }
+    }
+
+    // The code the peer is told when this error ends a test. Failures of the peer itself
+    // have nothing to tell it and local I/O failures end the test with Abort instead.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            NsptError::Io(_)
+            | NsptError::ServerBusy
+            | NsptError::PeerError { .. }
+            | NsptError::PeerAborted => None,
+            NsptError::Encode => Some(ErrorCode::INTERNAL),
+            NsptError::Decode
+            | NsptError::FrameTooLarge { .. }
+            | NsptError::TruncatedFrame { .. } => Some(ErrorCode::MALFORMED_MESSAGE),
+            NsptError::UnexpectedMessage { .. } | NsptError::OutOfOrder { .. } => {
+                Some(ErrorCode::UNEXPECTED_MESSAGE)
+            }
+            NsptError::VersionMismatch { .. } => Some(ErrorCode::VERSION_MISMATCH),
+            NsptError::Timeout => Some(ErrorCode::TIMEOUT),
+            NsptError::InvalidParameter(_) => Some(ErrorCode::INVALID_PARAMETER),
+            NsptError::Unsupported(_) => Some(ErrorCode::UNSUPPORTED),
+        }
+    }
+}
+
+impl fmt::Display for NsptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NsptError::Io(e) => write!(f, "I/O error: {e}"),
+            NsptError::Encode => write!(f, "Failed to encode message"),
+            NsptError::Decode => write!(f, "Failed to decode message"),
+            NsptError::UnexpectedMessage { expected, actual } => {
+                write!(f, "Protocol err: expected {expected}, but got {actual:?}")
+            }
+            NsptError::VersionMismatch { local, peer } => write!(
+                f,
+                "Protocol version mismatched! this proto-ver: {} but peer proto-ver: {}",
+                VersionRange(local),
+                VersionRange(peer)
+            ),
+            NsptError::Unsupported(missing) => write!(f, "Not supported on both sides: {missing}"),
+            NsptError::Timeout => write!(f, "Timed out waiting for the peer"),
+            NsptError::ServerBusy => write!(f, "Server is busy, try again later"),
+            NsptError::InvalidParameter(msg) => write!(f, "Invalid parameter: {msg}"),
+            NsptError::FrameTooLarge { size, max } => {
+                write!(f, "Frame of {size} bytes exceeds the limit of {max} bytes")
+            }
+            NsptError::TruncatedFrame { expected, actual } => {
+                write!(
+                    f,
+                    "Truncated frame: expected {expected} bytes, but got {actual}"
+                )
+            }
+            NsptError::PeerError { code, message } => {
+                write!(f, "Peer reported an error ({code}): {message}")
+            }
+            NsptError::PeerAborted => write!(f, "Peer aborted the test"),
+            NsptError::OutOfOrder {
+                state,
+                sender,
+                message,
+            } => write!(
+                f,
+                "Protocol err: {message} from the {sender} is out of order in state {state:?}"
+            ),
+        }
+    }
+}
+
+struct VersionRange<'a>(&'a RangeInclusive<ProtocolVer>);
+
+impl fmt::Display for VersionRange<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (min, max) = (self.0.start(), self.0.end());
+        if min == max {
+            write!(f, "{min:#04x}")
+        } else {
+            write!(f, "{min:#04x}..={max:#04x}")
+        }
+    }
+}
+
+impl std::error::Error for NsptError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NsptError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for NsptError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => NsptError::Timeout,
+            _ => NsptError::Io(e),
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use crate::{NsptError, DEFAULT_MAX_FRAME_SIZE};
+use rmp_serde::Serializer;
+use serde::{Deserialize, Serialize};
+use std::io::prelude::*;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Current frame: magic, type tag, u32 LE payload length, payload.
+// Legacy frame (protocol version 1): u64 LE payload length, payload. Its length can never
+// start with the magic, since that would be a frame of more than a gigabyte.
+pub const FRAME_MAGIC: [u8; 4] = *b"NSPT";
+pub const FRAME_TYPE_MESSAGE: u8 = 0x01; // rmp serialized NsptNegProtocol
+const FRAME_HEADER_SIZE: usize = FRAME_MAGIC.len() + size_of::<u8>() + size_of::<u32>();
+const LEGACY_FRAME_HEADER_SIZE: usize = size_of::<u64>();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameFormat {
+    Legacy, // the greeting and hellos to a version 1 peer
+    Current,
+}
+
+static MAX_FRAME_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_FRAME_SIZE);
+
+pub fn set_max_frame_size(max: usize) {
+    MAX_FRAME_SIZE.store(max, Ordering::Relaxed);
+}
+
+pub fn max_frame_size() -> usize {
+    MAX_FRAME_SIZE.load(Ordering::Relaxed)
+}
+
+#[derive(Debug)]
+pub struct SerializedDataContainer {
+    size: usize,
+    data: Vec<u8>,
+}
+
+impl SerializedDataContainer {
+    pub fn new(v: &[u8]) -> Self {
+        Self {
+            size: v.len(),
+            data: v.to_owned(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn to_one_vec_as(&self, format: FrameFormat) -> Result<Vec<u8>, NsptError> {
+        let mut ret = vec![];
+
+        match format {
+            FrameFormat::Legacy => ret.extend_from_slice(&(self.size as u64).to_le_bytes()),
+            FrameFormat::Current => {
+                let size = u32::try_from(self.size).map_err(|_| NsptError::FrameTooLarge {
+                    size: self.size,
+                    max: u32::MAX as usize,
+                })?;
+                ret.extend_from_slice(&FRAME_MAGIC);
+                ret.push(FRAME_TYPE_MESSAGE);
+                ret.extend_from_slice(&size.to_le_bytes());
+            }
+        }
+        ret.extend_from_slice(&self.data);
+
+        Ok(ret)
+    }
+
+    fn check_size(size: u64, max: usize) -> Result<usize, NsptError> {
+        usize::try_from(size)
+            .ok()
+            .filter(|size| *size <= max)
+            .ok_or(NsptError::FrameTooLarge {
+                size: size.try_into().unwrap_or(usize::MAX),
+                max,
+            })
+    }
+
+    // Parses either header format from its first bytes and returns the payload size.
+    fn parse_header(header: &[u8; FRAME_HEADER_SIZE], max: usize) -> Result<usize, NsptError> {
+        let size = if header[..FRAME_MAGIC.len()] == FRAME_MAGIC {
+            if header[FRAME_MAGIC.len()] != FRAME_TYPE_MESSAGE {
+                return Err(NsptError::Decode);
+            }
+            u32::from_le_bytes(header[FRAME_MAGIC.len() + 1..].try_into().unwrap()) as u64
+        } else {
+            u64::from_le_bytes(header[..LEGACY_FRAME_HEADER_SIZE].try_into().unwrap())
+        };
+
+        Self::check_size(size, max)
+    }
+
+    fn header_size(header: &[u8]) -> usize {
+        if header.starts_with(&FRAME_MAGIC) {
+            FRAME_HEADER_SIZE
+        } else {
+            LEGACY_FRAME_HEADER_SIZE
+        }
+    }
+
+    pub fn from_reader<T>(reader: &mut T) -> Result<Self, NsptError>
+    where
+        T: Read + ?Sized,
+    {
+        Self::from_reader_with_limit(reader, max_frame_size())
+    }
+
+    pub fn from_reader_with_limit<T>(reader: &mut T, max: usize) -> Result<Self, NsptError>
+    where
+        T: Read + ?Sized,
+    {
+        // Both headers are at least as long as the legacy one, which decides the format.
+        let mut header = [0; FRAME_HEADER_SIZE];
+        reader.read_exact(&mut header[..LEGACY_FRAME_HEADER_SIZE])?;
+        let header_size = Self::header_size(&header);
+        reader.read_exact(&mut header[LEGACY_FRAME_HEADER_SIZE..header_size])?;
+        let size = Self::parse_header(&header, max)?;
+
+        let mut data = Vec::with_capacity(size);
+        reader.take(size as u64).read_to_end(&mut data)?;
+        if data.len() < size {
+            return Err(NsptError::TruncatedFrame {
+                expected: size,
+                actual: data.len(),
+            });
+        }
+
+        Ok(Self { size, data })
+    }
+
+    pub fn from_one_vec(v: Vec<u8>) -> Result<Self, NsptError> {
+        let header_size = Self::header_size(&v);
+        let mut header = [0; FRAME_HEADER_SIZE];
+        header[..header_size].copy_from_slice(v.get(..header_size).ok_or(
+            NsptError::TruncatedFrame {
+                expected: header_size,
+                actual: v.len(),
+            },
+        )?);
+        let size = Self::parse_header(&header, max_frame_size())?;
+
+        let body = &v[header_size..];
+        let data = body.get(..size).ok_or(NsptError::TruncatedFrame {
+            expected: size,
+            actual: body.len(),
+        })?;
+
+        Ok(Self {
+            size,
+            data: data.to_vec(),
+        })
+    }
+
+    pub fn from_serializable_data<T>(t: &T) -> Option<Self>
+    where
+        T: Serialize,
+    {
+        let mut data = vec![];
+        t.serialize(&mut Serializer::new(&mut data)).ok().map(|_| {
+            let size = data.len();
+            Self { size, data }
+        })
+    }
+
+    pub fn to_serializable_data<T: for<'de> Deserialize<'de>>(&self) -> Option<T> {
+        rmp_serde::from_slice(&self.data).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: usize = 16;
+
+    fn current_header(size: u32) -> Vec<u8> {
+        let mut v = FRAME_MAGIC.to_vec();
+        v.push(FRAME_TYPE_MESSAGE);
+        v.extend_from_slice(&size.to_le_bytes());
+        v
+    }
+
+    #[test]
+    fn round_trips_both_formats() {
+        let frame = SerializedDataContainer::new(b"payload");
+        for format in [FrameFormat::Legacy, FrameFormat::Current] {
+            let v = frame.to_one_vec_as(format).unwrap();
+
+            let read = SerializedDataContainer::from_reader_with_limit(&mut &v[..], MAX).unwrap();
+            assert_eq!(read.data, b"payload");
+            let parsed = SerializedDataContainer::from_one_vec(v).unwrap();
+            assert_eq!(parsed.data, b"payload");
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_current_frame() {
+        let v = current_header(MAX as u32 + 1);
+        match SerializedDataContainer::from_reader_with_limit(&mut &v[..], MAX) {
+            Err(NsptError::FrameTooLarge { size, max }) => assert_eq!((size, max), (MAX + 1, MAX)),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_legacy_frame() {
+        let v = u64::MAX.to_le_bytes();
+        assert!(matches!(
+            SerializedDataContainer::from_reader_with_limit(&mut &v[..], MAX),
+            Err(NsptError::FrameTooLarge { max: MAX, .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_frame_without_its_payload() {
+        let v = current_header(u32::MAX);
+        assert!(matches!(
+            SerializedDataContainer::from_one_vec(v),
+            Err(NsptError::FrameTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_frame_type() {
+        let mut v = current_header(0);
+        v[FRAME_MAGIC.len()] = 0xff;
+        assert!(matches!(
+            SerializedDataContainer::from_reader_with_limit(&mut &v[..], MAX),
+            Err(NsptError::Decode)
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_payload() {
+        let mut v = current_header(8);
+        v.extend_from_slice(b"abc");
+        match SerializedDataContainer::from_reader_with_limit(&mut &v[..], MAX) {
+            Err(NsptError::TruncatedFrame { expected, actual }) => {
+                assert_eq!((expected, actual), (8, 3))
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        match SerializedDataContainer::from_one_vec(v) {
+            Err(NsptError::TruncatedFrame { expected, actual }) => {
+                assert_eq!((expected, actual), (8, 3))
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn rejects_short_header() {
+        for v in [vec![], vec![0; 3], FRAME_MAGIC.to_vec()] {
+            let len = v.len();
+            match SerializedDataContainer::from_one_vec(v) {
+                Err(NsptError::TruncatedFrame { actual, .. }) => assert_eq!(actual, len),
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+    }
+}
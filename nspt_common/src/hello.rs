@@ -0,0 +1,298 @@
+use crate::{
+    check_peer_error, send_message, send_message_as, FrameFormat, NsptError, NsptNegProtocol,
+    ProtocolVer, SerializedDataContainer, LEGACY_PROTOCOL_VER, PROTOCOL_VER,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::prelude::*;
+use std::ops::{BitAnd, BitOr, RangeInclusive};
+use std::str::FromStr;
+
+pub const MIN_PROTOCOL_VER: ProtocolVer = 0x0000_0000_0000_0002; // oldest version we speak
+
+// Optional features of the protocol. A test only uses the features both peers announce.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    pub const DOWNLOAD: Self = Self(1 << 0); // server -> client
+    pub const BIDIRECTIONAL: Self = Self(1 << 1);
+    pub const PARALLEL: Self = Self(1 << 2); // more than one data stream
+    pub const UDP: Self = Self(1 << 3);
+    pub const LATENCY: Self = Self(1 << 4);
+    pub const TIMED_ROUNDS: Self = Self(1 << 5); // NotifyRoundDuration
+    pub const OPEN_ENDED_ROUNDS: Self = Self(1 << 6); // rounds until EndOfTransfer
+    pub const ROUND_REPORT: Self = Self(1 << 7); // server side results in NotifyRoundReport
+    pub const ALL: Self = Self((1 << 8) - 1);
+
+    const NAMES: [(Self, &'static str); 8] = [
+        (Self::DOWNLOAD, "download"),
+        (Self::BIDIRECTIONAL, "bidirectional"),
+        (Self::PARALLEL, "parallel"),
+        (Self::UDP, "udp"),
+        (Self::LATENCY, "latency"),
+        (Self::TIMED_ROUNDS, "timed-rounds"),
+        (Self::OPEN_ENDED_ROUNDS, "open-ended-rounds"),
+        (Self::ROUND_REPORT, "round-report"),
+    ];
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = Self::NAMES
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| *name)
+            .collect();
+
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(","))
+        }
+    }
+}
+
+// Comma separated feature names, e.g. "udp,latency".
+impl FromStr for Capabilities {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .try_fold(Self::NONE, |acc, name| {
+                Self::NAMES
+                    .iter()
+                    .find(|(_, x)| x.eq_ignore_ascii_case(name))
+                    .map(|(capability, _)| acc | *capability)
+                    .ok_or_else(|| format!("Unknown capability: {name}"))
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Hello {
+    pub min_ver: ProtocolVer,
+    pub max_ver: ProtocolVer,
+    pub capabilities: Capabilities,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Negotiated {
+    pub version: ProtocolVer,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    pub fn new(capabilities: Capabilities) -> Self {
+        Self {
+            min_ver: MIN_PROTOCOL_VER,
+            max_ver: PROTOCOL_VER,
+            capabilities,
+        }
+    }
+
+    // A version 1 peer announces a single version and no capabilities.
+    pub fn legacy(ver: ProtocolVer) -> Self {
+        Self {
+            min_ver: ver,
+            max_ver: ver,
+            capabilities: Capabilities::NONE,
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.max_ver <= LEGACY_PROTOCOL_VER
+    }
+
+    pub fn versions(&self) -> RangeInclusive<ProtocolVer> {
+        self.min_ver..=self.max_ver
+    }
+
+    // Both peers come to the same result: the highest version in both ranges and the
+    // features both of them support.
+    pub fn negotiate(&self, peer: &Hello) -> Result<Negotiated, NsptError> {
+        let version = self.max_ver.min(peer.max_ver);
+        if version < self.min_ver.max(peer.min_ver) {
+            return Err(NsptError::VersionMismatch {
+                local: self.versions(),
+                peer: peer.versions(),
+            });
+        }
+
+        Ok(Negotiated {
+            version,
+            capabilities: self.capabilities & peer.capabilities,
+        })
+    }
+}
+
+impl Negotiated {
+    pub fn require(&self, required: Capabilities) -> Result<(), NsptError> {
+        let missing = required.difference(self.capabilities);
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(NsptError::Unsupported(missing))
+        }
+    }
+}
+
+// Hellos as a version 1 peer encodes them: the variant names match NsptNegProtocol, but
+// all they carry is the single version the peer speaks.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LegacyHello {
+    ClientHello(ProtocolVer),
+    ServerHello(ProtocolVer),
+}
+
+// Every connection starts with the server's version in the shape of a version 1
+// ServerHello, since that is what a version 1 client waits for before it says anything.
+// Such a client then fails on the version instead of waiting for a reply.
+pub fn send_greeting<W>(writer: &mut W) -> Result<(), NsptError>
+where
+    W: Write + ?Sized,
+{
+    send_message_as(
+        writer,
+        &LegacyHello::ServerHello(PROTOCOL_VER),
+        FrameFormat::Legacy,
+    )
+}
+
+// Returns the highest version the server speaks.
+pub fn recv_greeting<R>(reader: &mut R) -> Result<ProtocolVer, NsptError>
+where
+    R: Read + ?Sized,
+{
+    match SerializedDataContainer::from_reader(reader)?
+        .to_serializable_data()
+        .ok_or(NsptError::Decode)?
+    {
+        LegacyHello::ServerHello(ver) => Ok(ver),
+        LegacyHello::ClientHello(_) => Err(NsptError::Decode),
+    }
+}
+
+// Receives the first message of a connection, which may be the answer of a version 1
+// client to the greeting.
+pub fn recv_hello<R>(reader: &mut R) -> Result<NsptNegProtocol, NsptError>
+where
+    R: Read + ?Sized,
+{
+    let container = SerializedDataContainer::from_reader(reader)?;
+    if let Some(msg) = container.to_serializable_data() {
+        return check_peer_error(msg);
+    }
+
+    match container.to_serializable_data().ok_or(NsptError::Decode)? {
+        LegacyHello::ClientHello(ver) => Ok(NsptNegProtocol::ClientHello(Hello::legacy(ver), None)),
+        LegacyHello::ServerHello(_) => Err(NsptError::Decode),
+    }
+}
+
+// Sends a hello in the format of the server it goes to. A version 1 server only learns our
+// version, which is enough for it to close the connection instead of failing on it.
+pub fn send_hello<W>(writer: &mut W, msg: &NsptNegProtocol, peer: &Hello) -> Result<(), NsptError>
+where
+    W: Write + ?Sized,
+{
+    match *msg {
+        NsptNegProtocol::ClientHello(hello, _) if peer.is_legacy() => send_message_as(
+            writer,
+            &LegacyHello::ClientHello(hello.max_ver),
+            FrameFormat::Legacy,
+        ),
+        _ => send_message(writer, msg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Frames written by the version 1 release: a u64 LE length and a newtype variant.
+    const V1_CLIENT_HELLO: &[u8] = b"\x0e\0\0\0\0\0\0\0\x81\xabClientHello\x01";
+    const V1_SERVER_HELLO: &[u8] = b"\x0e\0\0\0\0\0\0\0\x81\xabServerHello\x01";
+
+    #[test]
+    fn recv_hello_decodes_v1_client_hello() {
+        match recv_hello(&mut &V1_CLIENT_HELLO[..]).unwrap() {
+            NsptNegProtocol::ClientHello(hello, None) => {
+                assert_eq!(hello.versions(), 1..=1);
+                assert!(hello.is_legacy());
+                assert!(hello.capabilities.is_empty());
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn greeting_is_a_v1_server_hello() {
+        let mut buf = vec![];
+        send_greeting(&mut buf).unwrap();
+
+        let mut expected = V1_SERVER_HELLO.to_vec();
+        *expected.last_mut().unwrap() = PROTOCOL_VER as u8;
+        assert_eq!(buf, expected);
+        assert_eq!(recv_greeting(&mut &V1_SERVER_HELLO[..]).unwrap(), 1);
+    }
+
+    #[test]
+    fn v1_hello_does_not_negotiate() {
+        let local = Hello::new(Capabilities::ALL);
+        match local.negotiate(&Hello::legacy(LEGACY_PROTOCOL_VER)) {
+            Err(NsptError::VersionMismatch { local: ours, peer }) => {
+                assert_eq!(ours, local.versions());
+                assert_eq!(peer, 1..=1);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn send_hello_to_v1_peer_uses_v1_shape() {
+        let mut buf = vec![];
+        let msg = NsptNegProtocol::ClientHello(Hello::new(Capabilities::ALL), None);
+        send_hello(&mut buf, &msg, &Hello::legacy(LEGACY_PROTOCOL_VER)).unwrap();
+
+        let mut expected = V1_CLIENT_HELLO.to_vec();
+        *expected.last_mut().unwrap() = PROTOCOL_VER as u8;
+        assert_eq!(buf, expected);
+    }
+}
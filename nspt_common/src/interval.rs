@@ -0,0 +1,202 @@
+use crate::{ThroughputReport, TransferReport};
+use serde::{Deserialize, Serialize};
+use std::io::prelude::*;
+use std::time::{Duration, Instant};
+
+// Counts the bytes moved in each fixed time slice since the round started.
+// A meter without an interval records nothing.
+#[derive(Debug, Clone)]
+pub struct IntervalMeter {
+    start: Instant,
+    interval: Option<Duration>,
+    slices: Vec<u64>,
+    last: Duration,
+}
+
+impl IntervalMeter {
+    pub fn new(start: Instant, interval: Option<Duration>) -> Self {
+        Self {
+            start,
+            interval,
+            slices: vec![],
+            last: Duration::ZERO,
+        }
+    }
+
+    pub fn record(&mut self, bytes: u64) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+
+        let elapsed = self.start.elapsed();
+        let slice = (elapsed.as_nanos() / interval.as_nanos()) as usize;
+        if self.slices.len() <= slice {
+            self.slices.resize(slice + 1, 0);
+        }
+        self.slices[slice] += bytes;
+        self.last = self.last.max(elapsed);
+    }
+}
+
+// The meters of one data stream; upload is client -> server on both sides.
+#[derive(Debug, Clone)]
+pub struct StreamMeters {
+    pub upload: IntervalMeter,
+    pub download: IntervalMeter,
+}
+
+impl StreamMeters {
+    pub fn new(start: Instant, interval: Option<Duration>) -> Self {
+        Self {
+            upload: IntervalMeter::new(start, interval),
+            download: IntervalMeter::new(start, interval),
+        }
+    }
+}
+
+// Feeds every successful read and write of the inner stream into a meter.
+pub struct MeteredStream<'a, S: ?Sized> {
+    inner: &'a mut S,
+    meter: &'a mut IntervalMeter,
+}
+
+impl<'a, S: ?Sized> MeteredStream<'a, S> {
+    pub fn new(inner: &'a mut S, meter: &'a mut IntervalMeter) -> Self {
+        Self { inner, meter }
+    }
+}
+
+impl<S: Read + ?Sized> Read for MeteredStream<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.meter.record(n as u64);
+        Ok(n)
+    }
+}
+
+impl<S: Write + ?Sized> Write for MeteredStream<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.meter.record(n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct IntervalReport {
+    pub start_secs: f64,
+    pub end_secs: f64,
+    pub throughput: ThroughputReport,
+}
+
+// A last slice shorter than this fraction of the interval is merged into the one before;
+// its rate would otherwise be extrapolated from a few microseconds.
+const MIN_TAIL_FRACTION: u32 = 4;
+
+impl IntervalReport {
+    // Sums the slices of all streams per direction. The last slice ends with the last
+    // recorded byte, so a short tail does not read as a drop.
+    pub fn collect<'a, U, D>(upload: U, download: D) -> Vec<Self>
+    where
+        U: IntoIterator<Item = &'a IntervalMeter>,
+        D: IntoIterator<Item = &'a IntervalMeter>,
+    {
+        let upload: Vec<_> = upload.into_iter().collect();
+        let download: Vec<_> = download.into_iter().collect();
+        let meters = || upload.iter().chain(&download);
+
+        let Some(interval) = meters().find_map(|x| x.interval) else {
+            return vec![];
+        };
+        let last = meters().map(|x| x.last).max().unwrap_or_default();
+        let mut count = meters().map(|x| x.slices.len()).max().unwrap_or(0);
+
+        let sum_slices = |meters: &[&IntervalMeter]| {
+            (!meters.is_empty()).then(|| {
+                (0..count)
+                    .map(|i| meters.iter().filter_map(|x| x.slices.get(i)).sum::<u64>())
+                    .collect::<Vec<_>>()
+            })
+        };
+        let mut upload = sum_slices(&upload);
+        let mut download = sum_slices(&download);
+
+        let tail = last.saturating_sub(interval * count.saturating_sub(1) as u32);
+        if count > 1 && tail < interval / MIN_TAIL_FRACTION {
+            for slices in [&mut upload, &mut download].into_iter().flatten() {
+                let tail = slices.pop().unwrap_or_default();
+                if let Some(slice) = slices.last_mut() {
+                    *slice += tail;
+                }
+            }
+            count -= 1;
+        }
+
+        (0..count)
+            .map(|i| {
+                let start = interval * i as u32;
+                let end = if i + 1 == count {
+                    last
+                } else {
+                    start + interval
+                };
+                let elapsed = if end > start { end - start } else { interval };
+                let transfer = |slices: &Option<Vec<u64>>| {
+                    slices.as_ref().map(|x| TransferReport::new(x[i], elapsed))
+                };
+
+                Self {
+                    start_secs: start.as_secs_f64(),
+                    end_secs: (start + elapsed).as_secs_f64(),
+                    throughput: ThroughputReport {
+                        upload: transfer(&upload),
+                        download: transfer(&download),
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meter(slices: Vec<u64>, last: Duration) -> IntervalMeter {
+        IntervalMeter {
+            start: Instant::now(),
+            interval: Some(Duration::from_millis(250)),
+            slices,
+            last,
+        }
+    }
+
+    #[test]
+    fn merges_a_short_tail() {
+        let upload = meter(
+            vec![100, 100, 100, 100, 1],
+            Duration::from_micros(1_000_010),
+        );
+        let reports = IntervalReport::collect([&upload], []);
+
+        assert_eq!(reports.len(), 4);
+        let last = &reports[3];
+        assert_eq!((last.start_secs, last.end_secs), (0.75, 1.00001));
+        assert_eq!(last.throughput.upload.unwrap().bytes, 101);
+        assert!(last.throughput.download.is_none());
+    }
+
+    #[test]
+    fn keeps_a_long_tail() {
+        let upload = meter(vec![100, 100, 50], Duration::from_millis(600));
+        let reports = IntervalReport::collect([&upload], []);
+
+        assert_eq!(reports.len(), 3);
+        assert_eq!((reports[1].start_secs, reports[1].end_secs), (0.25, 0.5));
+        assert_eq!((reports[2].start_secs, reports[2].end_secs), (0.5, 0.6));
+    }
+}
@@ -1,12 +1,38 @@
-use rmp_serde::Serializer;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use std::cmp::max;
+use std::fmt;
 use std::io::prelude::*;
-use std::mem::size_of;
+use std::io::ErrorKind;
+use std::iter::Sum;
 use std::net::{TcpListener, TcpStream};
+use std::ops::Add;
 #[cfg(not(target_os = "windows"))]
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+mod error;
+mod frame;
+mod hello;
+mod interval;
+mod report;
+mod round;
+mod state;
+mod stats;
+mod udp;
+mod units;
+pub use error::{ErrorCode, NsptError};
+pub use frame::*;
+pub use hello::*;
+pub use interval::*;
+pub use report::*;
+pub use round::*;
+pub use state::*;
+pub use stats::*;
+pub use udp::*;
+pub use units::*;
 
 pub const DEFAULT_SOCK_FILE: &str = "/tmp/nspt.sock";
 pub const SERVER_PORT: u16 = 12845;
@@ -14,12 +40,25 @@ pub const SERVER_PORT_S: &str = "12845";
 pub const TOTAL_SEND_NEG_BYTES: usize = 1024 * 1024 * 24; // 24 MB
 pub const MIN_SEND_BYTES: usize = 1024 * 1024 * 24; // 24 MB
 pub const BUF_SIZE: usize = 1024 << 6;
+pub const MAX_PARALLEL_STREAMS: u16 = 128;
+pub const MAX_LATENCY_MESSAGE_SIZE: usize = BUF_SIZE;
 pub type ProtocolVer = u64;
-pub const PROTOCOL_VER: ProtocolVer = 0x0000_0000_0000_0001;
-
-#[derive(Debug)]
+pub const PROTOCOL_VER: ProtocolVer = 0x0000_0000_0000_0002;
+pub const LEGACY_PROTOCOL_VER: ProtocolVer = 0x0000_0000_0000_0001; // usize framed peers
+pub type SessionCookie = u64;
+pub const CONTROL_TIMEOUT: Duration = Duration::from_secs(30);
+pub const MAX_ROUND_DURATION: Duration = Duration::from_secs(60 * 60); // 1 hour
+pub const ROUND_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Control messages are far smaller; the limit only keeps a peer from making us allocate
+// whatever its length prefix claims.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024; // 1 MB
+pub const MIN_MAX_FRAME_SIZE: usize = 64 * 1024; // 64 KB
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum TestMode {
     Tcp,
+    Udp,
     #[cfg(not(target_os = "windows"))]
     Unix,
 }
@@ -30,6 +69,7 @@ impl FromStr for TestMode {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "tcp" | "TCP" => Ok(TestMode::Tcp),
+            "udp" | "UDP" => Ok(TestMode::Udp),
             #[cfg(not(target_os = "windows"))]
             "unix" | "UNIX" => Ok(TestMode::Unix),
             _ => Err(format!("Unkown Test Mode: {s}")),
@@ -37,9 +77,128 @@ impl FromStr for TestMode {
     }
 }
 
+impl fmt::Display for TestMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TestMode::Tcp => write!(f, "tcp"),
+            TestMode::Udp => write!(f, "udp"),
+            #[cfg(not(target_os = "windows"))]
+            TestMode::Unix => write!(f, "unix"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TestDirection {
+    Upload,        // client -> server
+    Download,      // server -> client
+    Bidirectional, // both at the same time
+}
+
+impl TestDirection {
+    pub fn sends(self, role: Role) -> bool {
+        match role {
+            Role::Client => self != TestDirection::Download,
+            Role::Server => self != TestDirection::Upload,
+        }
+    }
+
+    pub fn receives(self, role: Role) -> bool {
+        self.sends(role.peer())
+    }
+}
+
+impl fmt::Display for TestDirection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TestDirection::Upload => write!(f, "upload"),
+            TestDirection::Download => write!(f, "download"),
+            TestDirection::Bidirectional => write!(f, "bidirectional"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RoundLimit {
+    Bytes(usize),
+    Duration(Duration),
+}
+
+impl fmt::Display for RoundLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoundLimit::Bytes(bytes) => {
+                write!(
+                    f,
+                    "{}({bytes})",
+                    get_human_friendly_data_size_str(*bytes as u64)
+                )
+            }
+            RoundLimit::Duration(duration) => write!(f, "{duration:?}"),
+        }
+    }
+}
+
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (value, unit) = match s.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let value: f64 = value
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid duration: {s}"))?;
+    let secs = match unit {
+        "ms" => value / 1000.,
+        "s" => value,
+        "m" => value * 60.,
+        _ => return Err(format!("Unknown duration unit: {unit}")),
+    };
+
+    Duration::try_from_secs_f64(secs)
+        .ok()
+        .filter(|d| !d.is_zero())
+        .ok_or_else(|| format!("Invalid duration: {s}"))
+}
+
+// Sizes use binary multiples, so "24M" equals MIN_SEND_BYTES. Accepts e.g. 1048576,
+// 512K, 512KiB, 512KB, 1.5G.
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let (value, unit) = match s.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return Err(format!("Unknown size unit: {unit}")),
+    };
+    let value = value.trim();
+
+    let size = if let Ok(value) = value.parse::<u64>() {
+        value.checked_mul(1 << shift)
+    } else {
+        value
+            .parse::<f64>()
+            .ok()
+            .filter(|x| x.is_finite() && *x >= 0.)
+            .map(|x| x * (1u64 << shift) as f64)
+            .filter(|x| *x <= u64::MAX as f64)
+            .map(|x| x as u64)
+    };
+
+    size.and_then(|x| usize::try_from(x).ok())
+        .ok_or_else(|| format!("Invalid size: {s}"))
+}
+
 pub trait ReadWriteStream: Read + Write + Send {
     fn try_clone(&self) -> std::io::Result<Box<dyn ReadWriteStream + Send>>;
     fn set_read_timeout(&self, dur: Option<std::time::Duration>) -> std::io::Result<()>;
+    fn set_nodelay(&self, nodelay: bool) -> std::io::Result<()>;
 }
 
 impl ReadWriteStream for TcpStream {
@@ -51,6 +210,10 @@ impl ReadWriteStream for TcpStream {
     fn set_read_timeout(&self, dur: Option<std::time::Duration>) -> std::io::Result<()> {
         self.set_read_timeout(dur)
     }
+
+    fn set_nodelay(&self, nodelay: bool) -> std::io::Result<()> {
+        self.set_nodelay(nodelay)
+    }
 }
 
 #[cfg(not(target_os = "windows"))]
@@ -63,6 +226,10 @@ impl ReadWriteStream for UnixStream {
     fn set_read_timeout(&self, dur: Option<std::time::Duration>) -> std::io::Result<()> {
         self.set_read_timeout(dur)
     }
+
+    fn set_nodelay(&self, _nodelay: bool) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 pub trait Listener<'a> {
@@ -98,47 +265,310 @@ fn find_next_power_of_two(n: u64) -> u64 {
     power_of_two
 }
 
-pub fn calc_transfer_size(bytes_per_ms: f64) -> usize {
-    let bytes_per_sec = (bytes_per_ms * 1000.) as u64;
+pub fn calc_transfer_size(throughput: Throughput) -> usize {
+    let bytes_per_sec = throughput.bytes_per_sec() as u64;
 
     let a = find_next_power_of_two(bytes_per_sec) as usize;
 
     max(a, MIN_SEND_BYTES)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+pub fn fill_random_bytes(buf: &mut [u8]) {
+    rand::thread_rng().fill_bytes(buf);
+}
+
+pub fn send_data<W>(writer: &mut W, buf: &[u8], transfer_size: usize) -> std::io::Result<()>
+where
+    W: Write + ?Sized,
+{
+    let mut remain = transfer_size;
+    while remain > 0 {
+        let next_size = remain.min(buf.len());
+        writer.write_all(&buf[..next_size])?;
+        remain -= next_size;
+    }
+    Ok(())
+}
+
+// Returns the time from the first received byte on. The peer starts sending only after
+// our last control message reached it, which must not count against its rate.
+pub fn recv_data<R>(
+    reader: &mut R,
+    buf: &mut [u8],
+    transfer_size: usize,
+) -> std::io::Result<Duration>
+where
+    R: Read + ?Sized,
+{
+    let mut start = None;
+    let mut remain = transfer_size;
+    while remain > 0 {
+        let next_size = remain.min(buf.len());
+        match reader.read(&mut buf[..next_size]) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                start.get_or_insert_with(Instant::now);
+                remain -= n;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(start.map_or(Duration::ZERO, |start| start.elapsed()))
+}
+
+pub fn send_data_for<W>(writer: &mut W, buf: &[u8], duration: Duration) -> std::io::Result<u64>
+where
+    W: Write + ?Sized,
+{
+    let start = Instant::now();
+    let mut sent = 0;
+    while start.elapsed() < duration {
+        writer.write_all(buf)?;
+        sent += buf.len() as u64;
+    }
+    Ok(sent)
+}
+
+// Receives until `target` bytes arrived. The target is unknown (u64::MAX) until the
+// sender reports it, so the reader must have a read timeout to notice the update.
+// Returns the received bytes and the time from the first to the last byte.
+pub fn recv_data_until<R>(
+    reader: &mut R,
+    buf: &mut [u8],
+    target: &AtomicU64,
+) -> std::io::Result<(u64, Duration)>
+where
+    R: Read + ?Sized,
+{
+    let mut start = None;
+    let mut elapsed = Duration::ZERO;
+    let mut received = 0;
+    loop {
+        let remain = target.load(Ordering::SeqCst).saturating_sub(received);
+        if remain == 0 {
+            return Ok((received, elapsed));
+        }
+
+        let next_size = remain.min(buf.len() as u64) as usize;
+        match reader.read(&mut buf[..next_size]) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                received += n as u64;
+                elapsed = start.get_or_insert_with(Instant::now).elapsed();
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                ) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub fn new_session_cookie() -> SessionCookie {
+    rand::random()
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LatencyTestParams {
+    pub message_size: usize,
+    pub transactions: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum NsptNegProtocol {
-    ClientHello(ProtocolVer),
-    ServerHello(ProtocolVer),
+    ClientHello(Hello, Option<SessionCookie>), // None -> control, Some -> data connection
+    ServerHello(Hello, SessionCookie),
     SpeedNegotiation(bool), // true -> perform, false -> skip
     StartSpeedNegotiation,
-    NotifyBufferSize(usize, u16), // unit buffer size, counts of test
+    NotifyBufferSize(usize, Option<u16>), // unit buffer size, counts of test (None -> open-ended)
     StartSpeedTest,
     EndOfSpeedTest,
     EndOfTransfer,
+    ServerBusy,
+    NotifyTestDirection(TestDirection),
+    NotifyStreamCount(u16),
+    NotifyUdpTest(UdpTestParams),
+    NotifyUdpPort(u16),
+    UdpTestReport(UdpStats),
+    NotifyLatencyTest(LatencyTestParams),
+    NotifyRoundDuration(Duration, Option<u16>), // duration of a round, counts of test
+    StartRound,
+    EndOfRound(Vec<u64>),                       // sent bytes of each stream
+    NotifyRoundReport(Vec<ThroughputReport>),   // server side measurement of each stream
+    Error { code: ErrorCode, message: String }, // the sender gives up on the test
+    Abort,                                      // the same, without a reason for the peer
+    EndOfUdpTransfer(u64),                      // datagrams the client sent
 }
 
-pub fn get_human_friendly_speed_str(bytes_per_ms: usize) -> String {
-    let bytes_per_sec = bytes_per_ms * 1000;
-    let bits_per_sec = bytes_per_sec * 8;
-    let k_bytes_per_sec = bytes_per_sec / 1024;
-    let k_bits_per_sec = k_bytes_per_sec * 8;
-    let m_bytes_per_sec = k_bytes_per_sec / 1024;
-    let m_bits_per_sec = m_bytes_per_sec * 8;
-    let g_bytes_per_sec = m_bytes_per_sec / 1024;
-    let g_bits_per_sec = g_bytes_per_sec * 8;
-
-    if g_bits_per_sec != 0 {
-        format!("{g_bits_per_sec} Gb/s")
-    } else if m_bits_per_sec != 0 {
-        format!("{m_bits_per_sec} Mb/s")
-    } else if k_bits_per_sec != 0 {
-        format!("{k_bits_per_sec} Kb/s")
-    } else {
-        format!("{bits_per_sec} b/s")
+impl NsptNegProtocol {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            NsptNegProtocol::ClientHello(..) => MessageKind::ClientHello,
+            NsptNegProtocol::ServerHello(..) => MessageKind::ServerHello,
+            NsptNegProtocol::SpeedNegotiation(_) => MessageKind::SpeedNegotiation,
+            NsptNegProtocol::StartSpeedNegotiation => MessageKind::StartSpeedNegotiation,
+            NsptNegProtocol::NotifyBufferSize(..) => MessageKind::NotifyBufferSize,
+            NsptNegProtocol::StartSpeedTest => MessageKind::StartSpeedTest,
+            NsptNegProtocol::EndOfSpeedTest => MessageKind::EndOfSpeedTest,
+            NsptNegProtocol::EndOfTransfer => MessageKind::EndOfTransfer,
+            NsptNegProtocol::ServerBusy => MessageKind::ServerBusy,
+            NsptNegProtocol::NotifyTestDirection(_) => MessageKind::NotifyTestDirection,
+            NsptNegProtocol::NotifyStreamCount(_) => MessageKind::NotifyStreamCount,
+            NsptNegProtocol::NotifyUdpTest(_) => MessageKind::NotifyUdpTest,
+            NsptNegProtocol::NotifyUdpPort(_) => MessageKind::NotifyUdpPort,
+            NsptNegProtocol::UdpTestReport(_) => MessageKind::UdpTestReport,
+            NsptNegProtocol::NotifyLatencyTest(_) => MessageKind::NotifyLatencyTest,
+            NsptNegProtocol::NotifyRoundDuration(..) => MessageKind::NotifyRoundDuration,
+            NsptNegProtocol::StartRound => MessageKind::StartRound,
+            NsptNegProtocol::EndOfRound(_) => MessageKind::EndOfRound,
+            NsptNegProtocol::NotifyRoundReport(_) => MessageKind::NotifyRoundReport,
+            NsptNegProtocol::Error { .. } => MessageKind::Error,
+            NsptNegProtocol::Abort => MessageKind::Abort,
+            NsptNegProtocol::EndOfUdpTransfer(_) => MessageKind::EndOfUdpTransfer,
+        }
+    }
+}
+
+// The variant of a NsptNegProtocol without its fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    ClientHello,
+    ServerHello,
+    SpeedNegotiation,
+    StartSpeedNegotiation,
+    NotifyBufferSize,
+    StartSpeedTest,
+    EndOfSpeedTest,
+    EndOfTransfer,
+    ServerBusy,
+    NotifyTestDirection,
+    NotifyStreamCount,
+    NotifyUdpTest,
+    NotifyUdpPort,
+    UdpTestReport,
+    NotifyLatencyTest,
+    NotifyRoundDuration,
+    StartRound,
+    EndOfRound,
+    NotifyRoundReport,
+    Error,
+    Abort,
+    EndOfUdpTransfer,
+}
+
+impl fmt::Display for MessageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
     }
 }
 
+// Either side may end a test with Error or Abort at any point, so every receive
+// turns them into the peer's reason.
+pub fn check_peer_error(msg: NsptNegProtocol) -> Result<NsptNegProtocol, NsptError> {
+    match msg {
+        NsptNegProtocol::Error { code, message } => Err(NsptError::PeerError { code, message }),
+        NsptNegProtocol::Abort => Err(NsptError::PeerAborted),
+        msg => Ok(msg),
+    }
+}
+
+// Tells the peer why the test ends. This is best effort: the connection may already
+// be gone, which is often the reason in the first place.
+pub fn report_error<W>(writer: &mut W, e: &NsptError)
+where
+    W: Write + ?Sized,
+{
+    let msg = match (e, e.code()) {
+        (_, Some(code)) => NsptNegProtocol::Error {
+            code,
+            message: e.to_string(),
+        },
+        (NsptError::Io(_), None) => NsptNegProtocol::Abort,
+        (_, None) => return,
+    };
+    let _ = send_message(writer, &msg);
+}
+
+pub fn send_message<W>(writer: &mut W, msg: &NsptNegProtocol) -> Result<(), NsptError>
+where
+    W: Write + ?Sized,
+{
+    send_message_as(writer, msg, FrameFormat::Current)
+}
+
+// The greeting and the hellos to a version 1 peer use the legacy framing, which is all
+// such a peer can read.
+pub fn send_message_as<W, T>(writer: &mut W, msg: &T, format: FrameFormat) -> Result<(), NsptError>
+where
+    W: Write + ?Sized,
+    T: Serialize,
+{
+    let container =
+        SerializedDataContainer::from_serializable_data(msg).ok_or(NsptError::Encode)?;
+    let max = max_frame_size();
+    if container.size() > max {
+        return Err(NsptError::FrameTooLarge {
+            size: container.size(),
+            max,
+        });
+    }
+    writer.write_all(&container.to_one_vec_as(format)?)?;
+    Ok(())
+}
+
+pub fn recv_message<R>(reader: &mut R) -> Result<NsptNegProtocol, NsptError>
+where
+    R: Read + ?Sized,
+{
+    SerializedDataContainer::from_reader(reader)?
+        .to_serializable_data()
+        .ok_or(NsptError::Decode)
+        .and_then(check_peer_error)
+}
+
+// Transfer rate in bytes per second.
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Throughput(pub f64);
+
+impl Throughput {
+    // Elapsed times below the clock resolution count as one nanosecond, so a fast
+    // transfer never divides by zero.
+    pub fn new(bytes: u64, elapsed: Duration) -> Self {
+        let elapsed = elapsed.max(Duration::from_nanos(1));
+        Throughput(bytes as f64 / elapsed.as_secs_f64())
+    }
+
+    pub fn bytes_per_sec(self) -> f64 {
+        self.0
+    }
+
+    pub fn bits_per_sec(self) -> f64 {
+        self.0 * 8.
+    }
+}
+
+impl Add for Throughput {
+    type Output = Throughput;
+
+    fn add(self, other: Self) -> Self {
+        Throughput(self.0 + other.0)
+    }
+}
+
+impl Sum for Throughput {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Throughput::default(), Add::add)
+    }
+}
+
+pub fn get_human_friendly_speed_str(throughput: Throughput) -> String {
+    speed_format().format(throughput)
+}
+
 pub fn get_human_friendly_data_size_str(bytes: u64) -> String {
     let k_bytes = bytes / 1024;
     let m_bytes = k_bytes / 1024;
@@ -155,73 +585,38 @@ pub fn get_human_friendly_data_size_str(bytes: u64) -> String {
     }
 }
 
-#[derive(Debug)]
-pub struct SerializedDataContainer {
-    size: usize,
-    data: Vec<u8>,
-}
-
-impl SerializedDataContainer {
-    pub fn new(v: &[u8]) -> Self {
-        Self {
-            size: v.len(),
-            data: v.to_owned(),
-        }
-    }
-
-    pub fn to_one_vec(&self) -> Vec<u8> {
-        let mut ret = vec![];
-
-        ret.append(&mut self.size.to_le_bytes().to_vec());
-        ret.append(&mut self.data.clone());
-
-        ret
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("24M"), Ok(MIN_SEND_BYTES));
+        assert_eq!(parse_size("512KiB"), Ok(512 * 1024));
+        assert_eq!(parse_size("512kb"), Ok(512 * 1024));
+        assert_eq!(parse_size("1.5G"), Ok(3 << 29));
+        assert_eq!(parse_size("1048576"), Ok(1048576));
     }
 
-    pub fn from_reader<T>(reader: &mut T) -> Result<Self, std::io::Error>
-    where
-        T: Read,
-    {
-        let mut size_buffer = [0; size_of::<usize>()];
-        reader.read_exact(&mut size_buffer).and_then(|_| {
-            let size = usize::from_le_bytes(size_buffer);
-            let mut data = vec![];
-
-            reader.take(size as u64).read_to_end(&mut data)?;
-
-            Ok(Self { size, data })
-        })
-    }
-
-    pub fn from_one_vec(v: Vec<u8>) -> Option<Self> {
-        if v.len() >= size_of::<usize>() {
-            let size = usize::from_le_bytes(
-                v[0..size_of::<usize>()]
-                    .try_into()
-                    .expect("Failed to parse size of the data container"),
-            );
-            let data = v[size_of::<usize>()..size_of::<usize>() + size]
-                .try_into()
-                .expect("Failed to get data of the data container");
-
-            Some(Self { size, data })
-        } else {
-            None
+    #[test]
+    fn rejects_invalid_sizes() {
+        for s in ["", "M", "24Q", "-1M", "-1", "1.5.0M", "99999999999T"] {
+            assert!(parse_size(s).is_err(), "{s}");
         }
     }
 
-    pub fn from_serializable_data<T>(t: &T) -> Option<Self>
-    where
-        T: Serialize,
-    {
-        let mut data = vec![];
-        t.serialize(&mut Serializer::new(&mut data)).ok().map(|_| {
-            let size = data.len();
-            Self { size, data }
-        })
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("10"), Ok(Duration::from_secs(10)));
     }
 
-    pub fn to_serializable_data<T: for<'de> Deserialize<'de>>(&self) -> Option<T> {
-        rmp_serde::from_slice(&self.data).ok()
+    #[test]
+    fn rejects_invalid_durations() {
+        for s in ["0", "0ms", "", "-1s", "5h", "ms"] {
+            assert!(parse_duration(s).is_err(), "{s}");
+        }
     }
 }
@@ -0,0 +1,328 @@
+use crate::{
+    get_human_friendly_speed_str, IntervalReport, ProtocolVer, TestDirection, TestMode, Throughput,
+    ThroughputStatistics, UdpStats,
+};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const CSV_HEADER: &str =
+    "timestamp,transport,target,round,direction,bytes,elapsed_secs,bits_per_second";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" | "TEXT" => Ok(OutputFormat::Text),
+            "json" | "JSON" => Ok(OutputFormat::Json),
+            "csv" | "CSV" => Ok(OutputFormat::Csv),
+            _ => Err(format!("Unknown output format: {s}")),
+        }
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TransferReport {
+    pub bytes: u64,
+    pub elapsed_secs: f64,
+    #[serde(rename = "bytes_per_second")]
+    pub throughput: Throughput,
+}
+
+impl TransferReport {
+    pub fn new(bytes: u64, elapsed: Duration) -> Self {
+        Self {
+            bytes,
+            elapsed_secs: elapsed.as_secs_f64(),
+            throughput: Throughput::new(bytes, elapsed),
+        }
+    }
+
+    // Streams run side by side, so their rates add up while the elapsed time is the longest one.
+    pub fn sum<'a, I>(transfers: I) -> Option<Self>
+    where
+        I: IntoIterator<Item = &'a TransferReport>,
+    {
+        transfers.into_iter().fold(None, |acc, x| {
+            Some(match acc {
+                None => *x,
+                Some(acc) => Self {
+                    bytes: acc.bytes + x.bytes,
+                    elapsed_secs: acc.elapsed_secs.max(x.elapsed_secs),
+                    throughput: acc.throughput + x.throughput,
+                },
+            })
+        })
+    }
+
+    pub fn average<'a, I>(transfers: I) -> Option<Self>
+    where
+        I: IntoIterator<Item = &'a TransferReport>,
+    {
+        let transfers: Vec<_> = transfers.into_iter().collect();
+        let n = transfers.len();
+        let sum = Self::sum(transfers.iter().copied())?;
+        let elapsed_secs = transfers.iter().map(|x| x.elapsed_secs).sum::<f64>();
+
+        Some(Self {
+            bytes: sum.bytes / n as u64,
+            elapsed_secs: elapsed_secs / n as f64,
+            throughput: Throughput(sum.throughput.bytes_per_sec() / n as f64),
+        })
+    }
+}
+
+// Also sent over the wire in NotifyRoundReport, so no field may be skipped.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct ThroughputReport {
+    pub upload: Option<TransferReport>,
+    pub download: Option<TransferReport>,
+}
+
+impl ThroughputReport {
+    pub fn sum<'a, I>(throughputs: I) -> Self
+    where
+        I: IntoIterator<Item = &'a ThroughputReport> + Clone,
+    {
+        Self {
+            upload: TransferReport::sum(throughputs.clone().into_iter().flat_map(|x| &x.upload)),
+            download: TransferReport::sum(throughputs.into_iter().flat_map(|x| &x.download)),
+        }
+    }
+
+    pub fn average<'a, I>(throughputs: I) -> Self
+    where
+        I: IntoIterator<Item = &'a ThroughputReport> + Clone,
+    {
+        Self {
+            upload: TransferReport::average(
+                throughputs.clone().into_iter().flat_map(|x| &x.upload),
+            ),
+            download: TransferReport::average(throughputs.into_iter().flat_map(|x| &x.download)),
+        }
+    }
+
+    // The client sends the upload and receives the download; the server does the opposite.
+    // Returns the (sender side, receiver side) views of a round.
+    pub fn by_role(client: &Self, server: &Self) -> (Self, Self) {
+        (
+            Self {
+                upload: client.upload,
+                download: server.download,
+            },
+            Self {
+                upload: server.upload,
+                download: client.download,
+            },
+        )
+    }
+
+    pub fn to_speed_str(&self, direction: TestDirection) -> String {
+        let upload = self.upload.map(|x| x.throughput).unwrap_or_default();
+        let download = self.download.map(|x| x.throughput).unwrap_or_default();
+
+        match direction {
+            TestDirection::Upload => get_human_friendly_speed_str(upload),
+            TestDirection::Download => get_human_friendly_speed_str(download),
+            TestDirection::Bidirectional => format!(
+                "upload: {}, download: {}, total: {}",
+                get_human_friendly_speed_str(upload),
+                get_human_friendly_speed_str(download),
+                get_human_friendly_speed_str(upload + download)
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoundReport {
+    pub round: u16,
+    pub timestamp: String, // RFC 3339, when the round finished
+    pub streams: Vec<ThroughputReport>,
+    pub sum: ThroughputReport,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub server_streams: Vec<ThroughputReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_sum: Option<ThroughputReport>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub intervals: Vec<IntervalReport>,
+}
+
+impl RoundReport {
+    pub fn new(round: u16, streams: Vec<ThroughputReport>, sum: ThroughputReport) -> Self {
+        Self {
+            round,
+            timestamp: chrono::Local::now().to_rfc3339(),
+            streams,
+            sum,
+            server_streams: vec![],
+            server_sum: None,
+            intervals: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatencyReport {
+    pub message_size: usize,
+    pub transactions: u32,
+    pub min_ns: u64,
+    pub avg_ns: u64,
+    pub max_ns: u64,
+    pub p50_ns: u64,
+    pub p99_ns: u64,
+    pub transactions_per_second: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestReport {
+    pub protocol_version: ProtocolVer,
+    pub transport: TestMode,
+    pub target: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub direction: Option<TestDirection>,
+    pub test_times: u16,
+    pub warmup: u16,
+    pub parallel: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transfer_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub round_duration_secs: Option<f64>,
+    pub rounds: Vec<RoundReport>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warmup_rounds: Vec<RoundReport>, // excluded from the average and statistics
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub average: Option<ThroughputReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_average: Option<ThroughputReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statistics: Option<ThroughputStatistics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub udp: Option<UdpStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency: Option<LatencyReport>,
+}
+
+impl TestReport {
+    pub fn new(transport: TestMode, target: String) -> Self {
+        Self {
+            protocol_version: crate::PROTOCOL_VER,
+            transport,
+            target,
+            direction: None,
+            test_times: 0,
+            warmup: 0,
+            parallel: 1,
+            transfer_size: None,
+            round_duration_secs: None,
+            rounds: vec![],
+            warmup_rounds: vec![],
+            average: None,
+            server_average: None,
+            statistics: None,
+            udp: None,
+            latency: None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Failed to serialize the test report")
+    }
+
+    // One line per round and direction, without a header so runs can be concatenated.
+    pub fn to_csv(&self) -> String {
+        let transport = self.transport;
+        let target = csv_field(&self.target);
+        let mut csv = String::new();
+
+        for round in &self.rounds {
+            let transfers = [
+                ("upload", round.sum.upload),
+                ("download", round.sum.download),
+            ];
+            for (direction, transfer) in transfers {
+                if let Some(transfer) = transfer {
+                    csv += &format!(
+                        "{},{transport},{target},{},{direction},{},{},{}\n",
+                        round.timestamp,
+                        round.round,
+                        transfer.bytes,
+                        transfer.elapsed_secs,
+                        transfer.throughput.bits_per_sec()
+                    );
+                }
+            }
+        }
+
+        csv
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunReport {
+    pub run: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub report: Option<TestReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RunReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Failed to serialize the run report")
+    }
+}
+
+// Result of `--repeat`: every run, including the failed ones, plus the average over
+// the runs that succeeded and statistics over all of their measured rounds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RepeatReport {
+    pub runs: Vec<RunReport>,
+    pub succeeded: u32,
+    pub failed: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub average: Option<ThroughputReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statistics: Option<ThroughputStatistics>,
+}
+
+impl RepeatReport {
+    pub fn new(runs: Vec<RunReport>) -> Self {
+        let reports: Vec<_> = runs.iter().filter_map(|x| x.report.as_ref()).collect();
+        let averages: Vec<_> = reports.iter().filter_map(|x| x.average.as_ref()).collect();
+        let rounds: Vec<_> = reports
+            .iter()
+            .flat_map(|x| &x.rounds)
+            .map(|x| &x.sum)
+            .collect();
+        let succeeded = reports.len() as u32;
+
+        Self {
+            failed: runs.len() as u32 - succeeded,
+            succeeded,
+            average: (!averages.is_empty()).then(|| ThroughputReport::average(averages)),
+            statistics: (!rounds.is_empty()).then(|| ThroughputStatistics::new(rounds)),
+            runs,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Failed to serialize the repeat report")
+    }
+}
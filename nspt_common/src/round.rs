@@ -0,0 +1,157 @@
+use crate::{
+    fill_random_bytes, recv_data_until, recv_expected, send_data_for, ControlStream, MeteredStream,
+    NsptError, NsptNegProtocol, ReadWriteStream, Role, StreamMeters, TestDirection,
+    ThroughputReport, TransferReport, BUF_SIZE,
+};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Runs one timed round on every data stream, for either role. The senders stop after
+// `duration`; the receivers learn where the round ends from the peer's EndOfRound.
+pub fn run_timed_round(
+    control: &mut ControlStream,
+    test_streams: &mut [Box<dyn ReadWriteStream + Send>],
+    duration: Duration,
+    direction: TestDirection,
+    meters: &mut [StreamMeters],
+) -> Result<Vec<ThroughputReport>, NsptError> {
+    let role = control.machine().role();
+    let mut recv_streams = Vec::new();
+    if direction.receives(role) {
+        for test_stream in test_streams.iter() {
+            recv_streams.push(test_stream.try_clone()?);
+        }
+    }
+    let targets: Vec<_> = recv_streams
+        .iter()
+        .map(|_| AtomicU64::new(u64::MAX))
+        .collect();
+    let stream_count = test_streams.len();
+    // Meters count client -> server traffic as upload, whichever side this is.
+    let (send_meters, recv_meters): (Vec<_>, Vec<_>) = meters
+        .iter_mut()
+        .map(|x| match role {
+            Role::Client => (&mut x.upload, &mut x.download),
+            Role::Server => (&mut x.download, &mut x.upload),
+        })
+        .unzip();
+
+    thread::scope(|s| {
+        let receivers: Vec<_> = recv_streams
+            .iter_mut()
+            .zip(&targets)
+            .zip(recv_meters)
+            .map(|((recv_stream, target), meter)| {
+                s.spawn(move || {
+                    let mut buf = [0; BUF_SIZE];
+                    recv_data_until(
+                        &mut MeteredStream::new(&mut **recv_stream, meter),
+                        &mut buf,
+                        target,
+                    )
+                })
+            })
+            .collect();
+
+        let senders: Vec<_> = if direction.sends(role) {
+            test_streams
+                .iter_mut()
+                .zip(send_meters)
+                .map(|(test_stream, meter)| {
+                    s.spawn(move || {
+                        let mut buf = [0; BUF_SIZE];
+                        fill_random_bytes(&mut buf);
+                        let start = Instant::now();
+                        send_data_for(
+                            &mut MeteredStream::new(&mut **test_stream, meter),
+                            &buf,
+                            duration,
+                        )
+                        .map(|sent| (sent, start.elapsed()))
+                    })
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+
+        let result = senders
+            .into_iter()
+            .map(|handle| handle.join().expect("Sender thread panicked"))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(NsptError::from)
+            .and_then(|sent| {
+                if role == Role::Client {
+                    control.send(&end_of_round(&sent, stream_count))?;
+                }
+                recv_end_of_round(control, stream_count, &targets)?;
+                Ok(sent)
+            });
+
+        // Unknown targets would keep the receivers waiting forever, so zero them on failure.
+        if result.is_err() {
+            targets
+                .iter()
+                .for_each(|target| target.store(0, Ordering::SeqCst));
+        }
+
+        let received = receivers
+            .into_iter()
+            .map(|handle| handle.join().expect("Receiver thread panicked"))
+            .collect::<Result<Vec<_>, _>>();
+
+        let sent = result?;
+        let received = received?;
+
+        // The server answers only once its receivers drained the round. The client starts
+        // the next round right after, and a pending read would take its first bytes.
+        if role == Role::Server {
+            control.send(&end_of_round(&sent, stream_count))?;
+        }
+
+        let transfer = |x: Option<&(u64, Duration)>| {
+            x.map(|(bytes, elapsed)| TransferReport::new(*bytes, *elapsed))
+        };
+        Ok((0..stream_count)
+            .map(|i| {
+                let (sent, received) = (transfer(sent.get(i)), transfer(received.get(i)));
+                match role {
+                    Role::Client => ThroughputReport {
+                        upload: sent,
+                        download: received,
+                    },
+                    Role::Server => ThroughputReport {
+                        upload: received,
+                        download: sent,
+                    },
+                }
+            })
+            .collect())
+    })
+}
+
+// The client ends the round first; each side tells how many bytes it sent per stream.
+fn end_of_round(sent: &[(u64, Duration)], stream_count: usize) -> NsptNegProtocol {
+    let mut counts: Vec<_> = sent.iter().map(|(bytes, _)| *bytes).collect();
+    counts.resize(stream_count, 0);
+    NsptNegProtocol::EndOfRound(counts)
+}
+
+// The peer's EndOfRound tells the receivers where the round ends.
+fn recv_end_of_round(
+    control: &mut ControlStream,
+    stream_count: usize,
+    targets: &[AtomicU64],
+) -> Result<(), NsptError> {
+    let peer_sent = recv_expected!(
+        control,
+        EndOfRound(peer_sent) if peer_sent.len() == stream_count => peer_sent
+    );
+    targets
+        .iter()
+        .zip(&peer_sent)
+        .for_each(|(target, bytes)| target.store(*bytes, Ordering::SeqCst));
+
+    Ok(())
+}
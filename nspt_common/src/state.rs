@@ -0,0 +1,587 @@
+use crate::{
+    recv_greeting, recv_message, report_error, send_hello, send_message, Capabilities, Hello,
+    MessageKind, NsptError, NsptNegProtocol, ProtocolVer, ReadWriteStream,
+};
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+impl Role {
+    pub fn peer(self) -> Self {
+        match self {
+            Role::Client => Role::Server,
+            Role::Server => Role::Client,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Client => write!(f, "client"),
+            Role::Server => write!(f, "server"),
+        }
+    }
+}
+
+// How the rounds of a stream test are delimited on the control connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rounds {
+    pub remaining: Option<u16>, // None -> open-ended
+    pub timed: bool,            // each round ends with EndOfRound from both sides
+    pub report: bool,           // the server sends NotifyRoundReport after each round
+}
+
+impl Rounds {
+    // Timed and open-ended rounds are started by the client with StartRound.
+    fn announced(&self) -> bool {
+        self.timed || self.remaining.is_none()
+    }
+
+    fn left(&self) -> bool {
+        self.remaining != Some(0)
+    }
+
+    fn next(self) -> Self {
+        Self {
+            remaining: self.remaining.map(|n| n.saturating_sub(1)),
+            ..self
+        }
+    }
+
+    // The state after StartRound. Untimed rounds without reports end unnoticed.
+    fn start(self) -> ProtocolState {
+        if self.timed {
+            ProtocolState::InRound(self, RoundStep::ClientEnd)
+        } else if self.report {
+            ProtocolState::InRound(self, RoundStep::Report)
+        } else {
+            ProtocolState::BetweenRounds(self.next())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundStep {
+    ClientEnd, // EndOfRound from the client next
+    ServerEnd, // EndOfRound from the server next
+    Report,    // NotifyRoundReport next
+}
+
+// Where a control connection is, named after what happened last.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolState {
+    Connected,        // ClientHello next
+    ClientHello,      // ServerHello or ServerBusy next
+    Negotiated,       // the client picks the test
+    StreamCount,      // NotifyTestDirection next
+    Direction,        // SpeedNegotiation next
+    SpeedNegotiation, // StartSpeedNegotiation from the client next
+    SpeedProbe,       // StartSpeedNegotiation from the server next
+    RoundSetup,       // NotifyBufferSize or NotifyRoundDuration next
+    RoundsAnnounced(Rounds),
+    BetweenRounds(Rounds),
+    InRound(Rounds, RoundStep),
+    UdpSetup,       // NotifyUdpPort next
+    UdpRunning,     // EndOfUdpTransfer next
+    UdpFinishing,   // UdpTestReport next
+    LatencySetup,   // StartSpeedTest next
+    LatencyRunning, // EndOfTransfer next
+    EndOfTransfer,  // EndOfSpeedTest next
+    Finished,       // after EndOfSpeedTest
+    Closed,         // after ServerBusy, Error, Abort or a failed negotiation
+}
+
+// The legal order of the control messages of a test, for either role. It only looks at
+// messages, so it can be driven without any connection.
+#[derive(Debug, Clone)]
+pub struct ProtocolMachine {
+    role: Role,
+    state: ProtocolState,
+    client_hello: Option<Hello>,
+    capabilities: Capabilities,
+}
+
+impl ProtocolMachine {
+    pub fn new(role: Role) -> Self {
+        Self {
+            role,
+            state: ProtocolState::Connected,
+            client_hello: None,
+            capabilities: Capabilities::NONE,
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn state(&self) -> ProtocolState {
+        self.state
+    }
+
+    // Features both peers agreed on; none until the hellos are exchanged.
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    pub fn on_send(&mut self, msg: &NsptNegProtocol) -> Result<(), NsptError> {
+        self.advance(self.role, msg)
+    }
+
+    pub fn on_recv(&mut self, msg: &NsptNegProtocol) -> Result<(), NsptError> {
+        self.advance(self.role.peer(), msg)
+    }
+
+    fn advance(&mut self, sender: Role, msg: &NsptNegProtocol) -> Result<(), NsptError> {
+        let next = self.next(sender, msg).ok_or(NsptError::OutOfOrder {
+            state: self.state,
+            sender,
+            message: msg.kind(),
+        })?;
+        self.state = next;
+        Ok(())
+    }
+
+    fn next(&mut self, sender: Role, msg: &NsptNegProtocol) -> Option<ProtocolState> {
+        use NsptNegProtocol as M;
+        use ProtocolState as S;
+        use Role::{Client, Server};
+
+        let state = match (self.state, sender, msg) {
+            (_, _, M::Error { .. } | M::Abort) => S::Closed,
+
+            (S::Connected, Client, M::ClientHello(hello, None)) => {
+                self.client_hello = Some(*hello);
+                S::ClientHello
+            }
+            (S::ClientHello, Server, M::ServerHello(hello, _)) => {
+                match self.client_hello?.negotiate(hello) {
+                    Ok(negotiated) => {
+                        self.capabilities = negotiated.capabilities;
+                        S::Negotiated
+                    }
+                    Err(_) => S::Closed,
+                }
+            }
+            (S::ClientHello, Server, M::ServerBusy) => S::Closed,
+
+            (S::Negotiated, Client, M::NotifyStreamCount(_)) => S::StreamCount,
+            (S::StreamCount, Client, M::NotifyTestDirection(_)) => S::Direction,
+            (S::Direction, Client, M::SpeedNegotiation(true)) => S::SpeedNegotiation,
+            (S::Direction, Client, M::SpeedNegotiation(false)) => S::RoundSetup,
+            (S::SpeedNegotiation, Client, M::StartSpeedNegotiation) => S::SpeedProbe,
+            (S::SpeedProbe, Server, M::StartSpeedNegotiation) => S::RoundSetup,
+            (S::RoundSetup, Client, M::NotifyBufferSize(_, test_times)) => {
+                S::RoundsAnnounced(self.rounds(*test_times, None))
+            }
+            (S::RoundSetup, Client, M::NotifyRoundDuration(duration, test_times)) => {
+                S::RoundsAnnounced(self.rounds(*test_times, Some(*duration)))
+            }
+            (S::RoundsAnnounced(rounds), Server, M::StartSpeedTest) => S::BetweenRounds(rounds),
+
+            (S::BetweenRounds(rounds), Client, M::StartRound)
+                if rounds.announced() && rounds.left() =>
+            {
+                rounds.start()
+            }
+            // Fixed byte rounds are not announced; only their reports mark them.
+            (S::BetweenRounds(rounds), Server, M::NotifyRoundReport(_))
+                if !rounds.announced() && rounds.report && rounds.left() =>
+            {
+                S::BetweenRounds(rounds.next())
+            }
+            (S::BetweenRounds(rounds), Client, M::EndOfTransfer)
+                if !rounds.left()
+                    || rounds.remaining.is_none()
+                    || !(rounds.announced() || rounds.report) =>
+            {
+                S::EndOfTransfer
+            }
+            (S::InRound(rounds, RoundStep::ClientEnd), Client, M::EndOfRound(_)) => {
+                S::InRound(rounds, RoundStep::ServerEnd)
+            }
+            (S::InRound(rounds, RoundStep::ServerEnd), Server, M::EndOfRound(_)) => {
+                if rounds.report {
+                    S::InRound(rounds, RoundStep::Report)
+                } else {
+                    S::BetweenRounds(rounds.next())
+                }
+            }
+            (S::InRound(rounds, RoundStep::Report), Server, M::NotifyRoundReport(_)) => {
+                S::BetweenRounds(rounds.next())
+            }
+
+            (S::Negotiated, Client, M::NotifyUdpTest(_)) => S::UdpSetup,
+            (S::UdpSetup, Server, M::NotifyUdpPort(_)) => S::UdpRunning,
+            (S::UdpRunning, Client, M::EndOfUdpTransfer(_)) => S::UdpFinishing,
+            (S::UdpFinishing, Server, M::UdpTestReport(_)) => S::EndOfTransfer,
+
+            (S::Negotiated, Client, M::NotifyLatencyTest(_)) => S::LatencySetup,
+            (S::LatencySetup, Server, M::StartSpeedTest) => S::LatencyRunning,
+            (S::LatencyRunning, Client, M::EndOfTransfer) => S::EndOfTransfer,
+
+            (S::EndOfTransfer, Server, M::EndOfSpeedTest) => S::Finished,
+            _ => return None,
+        };
+
+        Some(state)
+    }
+
+    fn rounds(&self, test_times: Option<u16>, duration: Option<Duration>) -> Rounds {
+        Rounds {
+            remaining: test_times,
+            timed: duration.is_some(),
+            report: self.capabilities.contains(Capabilities::ROUND_REPORT),
+        }
+    }
+}
+
+// The control connection of a test. Every message in either direction has to be legal
+// in the current state of the protocol.
+pub struct ControlStream {
+    stream: Box<dyn ReadWriteStream + Send>,
+    machine: ProtocolMachine,
+}
+
+impl ControlStream {
+    pub fn new(stream: Box<dyn ReadWriteStream + Send>, role: Role) -> Self {
+        Self {
+            stream,
+            machine: ProtocolMachine::new(role),
+        }
+    }
+
+    // The server reads the first message before it knows that the connection is a
+    // control connection.
+    pub fn accept(
+        stream: Box<dyn ReadWriteStream + Send>,
+        client_hello: &Hello,
+    ) -> Result<Self, NsptError> {
+        let mut control = Self::new(stream, Role::Server);
+        control
+            .machine
+            .on_recv(&NsptNegProtocol::ClientHello(*client_hello, None))?;
+        Ok(control)
+    }
+
+    pub fn machine(&self) -> &ProtocolMachine {
+        &self.machine
+    }
+
+    pub fn send(&mut self, msg: &NsptNegProtocol) -> Result<(), NsptError> {
+        self.machine.on_send(msg)?;
+        send_message(&mut self.stream, msg)
+    }
+
+    pub fn send_hello(&mut self, msg: &NsptNegProtocol, peer: &Hello) -> Result<(), NsptError> {
+        self.machine.on_send(msg)?;
+        send_hello(&mut self.stream, msg, peer)
+    }
+
+    pub fn recv(&mut self) -> Result<NsptNegProtocol, NsptError> {
+        let msg = recv_message(&mut self.stream)?;
+        self.machine.on_recv(&msg)?;
+        Ok(msg)
+    }
+
+    // For states in which only one message can come from the peer.
+    pub fn expect(&mut self, expected: MessageKind) -> Result<(), NsptError> {
+        match self.recv()? {
+            msg if msg.kind() == expected => Ok(()),
+            other => Err(NsptError::unexpected(expected, other)),
+        }
+    }
+
+    // The greeting comes before any message of the protocol, so the machine never sees it.
+    pub fn recv_greeting(&mut self) -> Result<ProtocolVer, NsptError> {
+        recv_greeting(&mut self.stream)
+    }
+
+    pub fn report_error(&mut self, e: &NsptError) {
+        report_error(&mut self.stream, e);
+        self.machine.state = ProtocolState::Closed;
+    }
+
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> std::io::Result<()> {
+        self.stream.set_read_timeout(dur)
+    }
+}
+
+// Receives the one message the peer can send next on a ControlStream and takes its
+// fields apart. The expected kind comes from the variant, so it cannot be misspelled.
+#[macro_export]
+macro_rules! recv_expected {
+    ($control:expr, $variant:ident $(($($field:pat),*))? $(if $guard:expr)? => $value:expr) => {
+        match $control.recv()? {
+            $crate::NsptNegProtocol::$variant $(($($field),*))? $(if $guard)? => $value,
+            other => {
+                return Err($crate::NsptError::unexpected(
+                    $crate::MessageKind::$variant,
+                    other,
+                ))
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ErrorCode, LatencyTestParams, TestDirection, UdpStats, UdpTestParams};
+    use NsptNegProtocol as M;
+    use Role::{Client, Server};
+
+    fn hellos(capabilities: Capabilities) -> Vec<(Role, NsptNegProtocol)> {
+        vec![
+            (Client, M::ClientHello(Hello::new(capabilities), None)),
+            (Server, M::ServerHello(Hello::new(capabilities), 0)),
+        ]
+    }
+
+    fn stream_setup() -> Vec<(Role, NsptNegProtocol)> {
+        vec![
+            (Client, M::NotifyStreamCount(1)),
+            (Client, M::NotifyTestDirection(TestDirection::Upload)),
+            (Client, M::SpeedNegotiation(false)),
+        ]
+    }
+
+    fn end_of_test() -> Vec<(Role, NsptNegProtocol)> {
+        vec![(Client, M::EndOfTransfer), (Server, M::EndOfSpeedTest)]
+    }
+
+    fn error() -> NsptNegProtocol {
+        M::Error {
+            code: ErrorCode::INTERNAL,
+            message: String::new(),
+        }
+    }
+
+    // Every legal sequence of a test, with the capabilities it is negotiated with.
+    fn sequences() -> Vec<(Capabilities, Vec<(Role, NsptNegProtocol)>)> {
+        let fixed = [
+            stream_setup(),
+            vec![
+                (Client, M::NotifyBufferSize(1024, Some(2))),
+                (Server, M::StartSpeedTest),
+            ],
+            end_of_test(),
+        ]
+        .concat();
+        let fixed_with_report = [
+            stream_setup(),
+            vec![
+                (Client, M::NotifyBufferSize(1024, Some(2))),
+                (Server, M::StartSpeedTest),
+                (Server, M::NotifyRoundReport(vec![])),
+                (Server, M::NotifyRoundReport(vec![])),
+            ],
+            end_of_test(),
+        ]
+        .concat();
+        let timed = [
+            vec![
+                (Client, M::NotifyStreamCount(1)),
+                (Client, M::NotifyTestDirection(TestDirection::Upload)),
+                (Client, M::SpeedNegotiation(true)),
+                (Client, M::StartSpeedNegotiation),
+                (Server, M::StartSpeedNegotiation),
+                (
+                    Client,
+                    M::NotifyRoundDuration(Duration::from_secs(1), Some(1)),
+                ),
+                (Server, M::StartSpeedTest),
+                (Client, M::StartRound),
+                (Client, M::EndOfRound(vec![0])),
+                (Server, M::EndOfRound(vec![0])),
+                (Server, M::NotifyRoundReport(vec![])),
+            ],
+            end_of_test(),
+        ]
+        .concat();
+        let open_ended = [
+            stream_setup(),
+            vec![
+                (Client, M::NotifyBufferSize(1024, None)),
+                (Server, M::StartSpeedTest),
+                (Client, M::StartRound),
+                (Server, M::NotifyRoundReport(vec![])),
+                (Client, M::StartRound),
+                (Server, M::NotifyRoundReport(vec![])),
+            ],
+            end_of_test(),
+        ]
+        .concat();
+        let udp = vec![
+            (
+                Client,
+                M::NotifyUdpTest(UdpTestParams {
+                    bitrate: 1_000_000,
+                    packet_size: 1024,
+                    round_duration: Duration::from_secs(1),
+                    test_times: 1,
+                }),
+            ),
+            (Server, M::NotifyUdpPort(5201)),
+            (Client, M::EndOfUdpTransfer(10)),
+            (Server, M::UdpTestReport(UdpStats::default())),
+            (Server, M::EndOfSpeedTest),
+        ];
+        let latency = [
+            vec![
+                (
+                    Client,
+                    M::NotifyLatencyTest(LatencyTestParams {
+                        message_size: 1,
+                        transactions: 10,
+                    }),
+                ),
+                (Server, M::StartSpeedTest),
+            ],
+            end_of_test(),
+        ]
+        .concat();
+
+        let report = Capabilities::ALL;
+        let no_report = Capabilities::ALL.difference(Capabilities::ROUND_REPORT);
+        [
+            (no_report, fixed),
+            (report, fixed_with_report),
+            (report, timed),
+            (report, open_ended),
+            (report, udp),
+            (report, latency),
+        ]
+        .into_iter()
+        .map(|(capabilities, test)| (capabilities, [hellos(capabilities), test].concat()))
+        .collect()
+    }
+
+    fn advance(
+        machine: &mut ProtocolMachine,
+        sender: Role,
+        msg: &NsptNegProtocol,
+    ) -> Result<(), NsptError> {
+        if sender == machine.role() {
+            machine.on_send(msg)
+        } else {
+            machine.on_recv(msg)
+        }
+    }
+
+    #[test]
+    fn accepts_legal_sequences() {
+        for (_, sequence) in sequences() {
+            for role in [Client, Server] {
+                let mut machine = ProtocolMachine::new(role);
+                for (sender, msg) in &sequence {
+                    advance(&mut machine, *sender, msg)
+                        .unwrap_or_else(|e| panic!("{role}: {msg:?} in {sequence:?}: {e}"));
+                }
+                assert_eq!(machine.state(), ProtocolState::Finished, "{sequence:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn server_busy_closes() {
+        let mut machine = ProtocolMachine::new(Client);
+        advance(&mut machine, Client, &hellos(Capabilities::ALL)[0].1).unwrap();
+        advance(&mut machine, Server, &M::ServerBusy).unwrap();
+        assert_eq!(machine.state(), ProtocolState::Closed);
+    }
+
+    #[test]
+    fn error_and_abort_close_from_any_state() {
+        for (_, sequence) in sequences() {
+            let mut machine = ProtocolMachine::new(Client);
+            for (sender, msg) in &sequence {
+                for end in [error(), M::Abort] {
+                    for peer in [Client, Server] {
+                        let mut ended = machine.clone();
+                        advance(&mut ended, peer, &end).unwrap();
+                        assert_eq!(ended.state(), ProtocolState::Closed);
+                    }
+                }
+                advance(&mut machine, *sender, msg).unwrap();
+            }
+        }
+    }
+
+    fn assert_out_of_order(
+        capabilities: Capabilities,
+        legal: &[(Role, NsptNegProtocol)],
+        sender: Role,
+        msg: NsptNegProtocol,
+    ) {
+        let mut machine = ProtocolMachine::new(Client);
+        for (legal_sender, legal_msg) in [hellos(capabilities), legal.to_vec()].concat() {
+            advance(&mut machine, legal_sender, &legal_msg).unwrap();
+        }
+        let state = machine.state();
+        match advance(&mut machine, sender, &msg) {
+            Err(NsptError::OutOfOrder {
+                state: rejected_in,
+                sender: rejected_sender,
+                message,
+            }) => {
+                assert_eq!(rejected_in, state);
+                assert_eq!(rejected_sender, sender);
+                assert_eq!(message, msg.kind());
+            }
+            other => panic!("{msg:?} from the {sender} in {state:?}: {other:?}"),
+        }
+        assert_eq!(machine.state(), state);
+    }
+
+    #[test]
+    fn rejects_out_of_order_messages() {
+        let all = Capabilities::ALL;
+        let rounds = [
+            stream_setup(),
+            vec![
+                (Client, M::NotifyBufferSize(1024, Some(2))),
+                (Server, M::StartSpeedTest),
+            ],
+        ]
+        .concat();
+
+        // Before the hellos.
+        let mut machine = ProtocolMachine::new(Server);
+        assert!(machine.on_recv(&M::NotifyStreamCount(1)).is_err());
+        // A data connection hello on the control connection.
+        assert!(machine
+            .on_recv(&M::ClientHello(Hello::new(all), Some(1)))
+            .is_err());
+
+        // The server picks no test.
+        assert_out_of_order(all, &[], Server, M::NotifyStreamCount(1));
+        assert_out_of_order(all, &[], Client, M::StartSpeedTest);
+        // Skipped setup.
+        assert_out_of_order(
+            all,
+            &stream_setup()[..1],
+            Client,
+            M::SpeedNegotiation(false),
+        );
+        // Rounds before the server starts the test.
+        assert_out_of_order(all, &rounds[..4], Client, M::EndOfTransfer);
+        // The test ends before the announced rounds did.
+        assert_out_of_order(all, &rounds, Client, M::EndOfTransfer);
+        // Fixed byte rounds are never announced.
+        assert_out_of_order(all, &rounds, Client, M::StartRound);
+        // No report without the capability.
+        let no_report = all.difference(Capabilities::ROUND_REPORT);
+        assert_out_of_order(no_report, &rounds, Server, M::NotifyRoundReport(vec![]));
+        // EndOfTransfer does not end a UDP test.
+        let (_, udp) = &sequences()[4];
+        assert!(matches!(udp[2].1, M::NotifyUdpTest(_)));
+        assert_out_of_order(all, &udp[2..4], Client, M::EndOfTransfer);
+    }
+}
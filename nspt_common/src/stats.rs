@@ -0,0 +1,186 @@
+use crate::ThroughputReport;
+use serde::{Deserialize, Serialize};
+
+// Two-sided 95% critical values of Student's t distribution for 1..=30 degrees of freedom.
+const T_95: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+const Z_95: f64 = 1.96;
+
+// Linear interpolation between the closest ranks; `sorted` must be non-empty.
+pub fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (sorted.len() - 1) as f64 * p / 100.;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Summary {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub median: f64,
+    pub stddev: f64, // sample standard deviation
+    pub p5: f64,
+    pub p95: f64,
+    pub ci95_low: f64, // 95% confidence interval of the mean
+    pub ci95_high: f64,
+    pub relative_standard_error: f64, // standard error of the mean divided by the mean
+}
+
+impl Summary {
+    pub fn new(samples: &[f64]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(f64::total_cmp);
+
+        let count = sorted.len();
+        let mean = sorted.iter().sum::<f64>() / count as f64;
+        let stddev = if count > 1 {
+            let var = sorted.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (count - 1) as f64;
+            var.sqrt()
+        } else {
+            0.
+        };
+        let standard_error = stddev / (count as f64).sqrt();
+        let t = T_95.get(count.saturating_sub(2)).copied().unwrap_or(Z_95);
+        let margin = t * standard_error;
+
+        Some(Self {
+            count,
+            min: sorted[0],
+            max: sorted[count - 1],
+            mean,
+            median: percentile(&sorted, 50.),
+            stddev,
+            p5: percentile(&sorted, 5.),
+            p95: percentile(&sorted, 95.),
+            ci95_low: mean - margin,
+            ci95_high: mean + margin,
+            relative_standard_error: if mean > 0. {
+                standard_error / mean
+            } else {
+                f64::INFINITY
+            },
+        })
+    }
+}
+
+// Summaries of the per-round bytes per second of each direction.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct ThroughputStatistics {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload: Option<Summary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download: Option<Summary>,
+}
+
+impl ThroughputStatistics {
+    pub fn new<'a, I>(rounds: I) -> Self
+    where
+        I: IntoIterator<Item = &'a ThroughputReport>,
+    {
+        let (upload, download): (Vec<_>, Vec<_>) = rounds
+            .into_iter()
+            .map(|x| {
+                (
+                    x.upload.map(|x| x.throughput.bytes_per_sec()),
+                    x.download.map(|x| x.throughput.bytes_per_sec()),
+                )
+            })
+            .unzip();
+        let summary = |samples: Vec<Option<f64>>| {
+            Summary::new(&samples.into_iter().flatten().collect::<Vec<_>>())
+        };
+
+        Self {
+            upload: summary(upload),
+            download: summary(download),
+        }
+    }
+
+    pub fn max_relative_standard_error(&self) -> Option<f64> {
+        [self.upload, self.download]
+            .iter()
+            .flatten()
+            .map(|x| x.relative_standard_error)
+            .reduce(f64::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{actual} is not {expected}"
+        );
+    }
+
+    #[test]
+    fn empty_has_no_summary() {
+        assert!(Summary::new(&[]).is_none());
+    }
+
+    #[test]
+    fn single_sample() {
+        let summary = Summary::new(&[3.]).unwrap();
+        assert_eq!(summary.count, 1);
+        assert_eq!((summary.min, summary.max, summary.median), (3., 3., 3.));
+        assert_eq!((summary.p5, summary.p95), (3., 3.));
+        assert_eq!(summary.stddev, 0.);
+        assert_eq!((summary.ci95_low, summary.ci95_high), (3., 3.));
+        assert_eq!(summary.relative_standard_error, 0.);
+    }
+
+    #[test]
+    fn small_sample() {
+        // Mean 5, sum of squared deviations 32 over 7 degrees of freedom.
+        let summary = Summary::new(&[5., 2., 9., 4., 7., 4., 5., 4.]).unwrap();
+        assert_eq!(summary.count, 8);
+        assert_eq!((summary.min, summary.max), (2., 9.));
+        assert_close(summary.mean, 5.);
+        assert_close(summary.median, 4.5);
+        assert_close(summary.p5, 2.7); // rank 0.35 between 2 and 4
+        assert_close(summary.p95, 8.3); // rank 6.65 between 7 and 9
+        let stddev = (32f64 / 7.).sqrt();
+        assert_close(summary.stddev, stddev);
+        let margin = 2.365 * stddev / 8f64.sqrt();
+        assert_close(summary.ci95_low, 5. - margin);
+        assert_close(summary.ci95_high, 5. + margin);
+        assert_close(summary.relative_standard_error, stddev / 8f64.sqrt() / 5.);
+    }
+
+    #[test]
+    fn large_sample_uses_normal_quantile() {
+        let samples: Vec<_> = (0..40).map(|x| (x % 2) as f64).collect();
+        let summary = Summary::new(&samples).unwrap();
+        let margin = Z_95 * summary.stddev / 40f64.sqrt();
+        assert_close(summary.ci95_high - summary.mean, margin);
+    }
+
+    #[test]
+    fn zero_mean_has_infinite_rse() {
+        let summary = Summary::new(&[0., 0.]).unwrap();
+        assert_eq!(summary.relative_standard_error, f64::INFINITY);
+        assert_eq!((summary.ci95_low, summary.ci95_high), (0., 0.));
+    }
+
+    #[test]
+    fn percentile_interpolates_between_ranks() {
+        let sorted = [10., 20., 30.];
+        assert_eq!(percentile(&sorted, 0.), 10.);
+        assert_eq!(percentile(&sorted, 25.), 15.);
+        assert_eq!(percentile(&sorted, 50.), 20.);
+        assert_eq!(percentile(&sorted, 100.), 30.);
+    }
+}
@@ -0,0 +1,221 @@
+use serde::{Deserialize, Serialize};
+use std::mem::size_of;
+use std::time::Duration;
+
+pub const UDP_HEADER_SIZE: usize = size_of::<u64>() * 2; // sequence number, sent timestamp
+pub const MAX_UDP_PACKET_SIZE: usize = 65507;
+pub const UDP_ROUND_DURATION: Duration = Duration::from_secs(1);
+pub const UDP_GRACE_PERIOD: Duration = Duration::from_millis(250);
+// Datagrams up to this many sequence numbers behind the newest one are checked for
+// duplicates. Older ones are too late to tell and count as reordered.
+pub const UDP_REORDER_WINDOW: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct UdpTestParams {
+    pub bitrate: u64, // bits per sec
+    pub packet_size: usize,
+    pub round_duration: Duration,
+    pub test_times: u16,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct UdpStats {
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    pub packets_lost: u64,
+    pub packets_duplicated: u64,
+    pub packets_reordered: u64,
+    pub jitter_ns: u64,
+}
+
+impl UdpStats {
+    pub fn loss_percent(&self) -> f64 {
+        let expected = self.packets_received + self.packets_lost;
+        if expected == 0 {
+            0.
+        } else {
+            self.packets_lost as f64 * 100. / expected as f64
+        }
+    }
+}
+
+pub fn write_udp_header(buf: &mut [u8], seq: u64, sent_ns: u64) {
+    buf[..size_of::<u64>()].copy_from_slice(&seq.to_le_bytes());
+    buf[size_of::<u64>()..UDP_HEADER_SIZE].copy_from_slice(&sent_ns.to_le_bytes());
+}
+
+pub fn read_udp_header(buf: &[u8]) -> Option<(u64, u64)> {
+    if buf.len() < UDP_HEADER_SIZE {
+        return None;
+    }
+
+    let seq = u64::from_le_bytes(buf[..size_of::<u64>()].try_into().ok()?);
+    let sent_ns = u64::from_le_bytes(buf[size_of::<u64>()..UDP_HEADER_SIZE].try_into().ok()?);
+
+    Some((seq, sent_ns))
+}
+
+#[derive(Debug)]
+pub struct UdpStatsCollector {
+    seen: Vec<bool>, // by sequence number modulo UDP_REORDER_WINDOW, up to max_seq
+    max_seq: Option<u64>,
+    packets_received: u64,
+    bytes_received: u64,
+    packets_duplicated: u64,
+    packets_reordered: u64,
+    last_transit: Option<i128>,
+    jitter: f64,
+}
+
+impl Default for UdpStatsCollector {
+    fn default() -> Self {
+        Self {
+            seen: vec![false; UDP_REORDER_WINDOW as usize],
+            max_seq: None,
+            packets_received: 0,
+            bytes_received: 0,
+            packets_duplicated: 0,
+            packets_reordered: 0,
+            last_transit: None,
+            jitter: 0.,
+        }
+    }
+}
+
+impl UdpStatsCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn slot(seq: u64) -> usize {
+        (seq % UDP_REORDER_WINDOW) as usize
+    }
+
+    pub fn record(&mut self, seq: u64, sent_ns: u64, arrival_ns: u64, len: usize) {
+        let in_window = match self.max_seq {
+            Some(max_seq) if seq > max_seq => {
+                // The slots of skipped sequence numbers now belong to the new window.
+                let first = (max_seq + 1).max(seq.saturating_sub(UDP_REORDER_WINDOW - 1));
+                (first..seq).for_each(|x| self.seen[Self::slot(x)] = false);
+                self.max_seq = Some(seq);
+                true
+            }
+            // Its slot already belongs to a newer sequence number.
+            Some(max_seq) if max_seq - seq >= UDP_REORDER_WINDOW => {
+                self.packets_reordered += 1;
+                false
+            }
+            Some(_) if self.seen[Self::slot(seq)] => {
+                self.packets_duplicated += 1;
+                return;
+            }
+            Some(_) => {
+                self.packets_reordered += 1;
+                true
+            }
+            None => {
+                self.max_seq = Some(seq);
+                true
+            }
+        };
+        if in_window {
+            self.seen[Self::slot(seq)] = true;
+        }
+
+        self.packets_received += 1;
+        self.bytes_received += len as u64;
+
+        // RFC 3550 interarrival jitter; the clock offset between peers cancels out.
+        let transit = arrival_ns as i128 - sent_ns as i128;
+        if let Some(last_transit) = self.last_transit {
+            let d = (transit - last_transit).abs() as f64;
+            self.jitter += (d - self.jitter) / 16.;
+        }
+        self.last_transit = Some(transit);
+    }
+
+    pub fn stats(&self, packets_sent: u64) -> UdpStats {
+        UdpStats {
+            packets_received: self.packets_received,
+            bytes_received: self.bytes_received,
+            packets_lost: packets_sent.saturating_sub(self.packets_received),
+            packets_duplicated: self.packets_duplicated,
+            packets_reordered: self.packets_reordered,
+            jitter_ns: self.jitter as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(seqs: &[u64]) -> UdpStatsCollector {
+        let mut collector = UdpStatsCollector::new();
+        for &seq in seqs {
+            collector.record(seq, 0, 0, 100);
+        }
+        collector
+    }
+
+    #[test]
+    fn in_order_run() {
+        let stats = collect(&[0, 1, 2, 3]).stats(4);
+        assert_eq!(stats.packets_received, 4);
+        assert_eq!(stats.bytes_received, 400);
+        assert_eq!(stats.packets_lost, 0);
+        assert_eq!(stats.packets_duplicated, 0);
+        assert_eq!(stats.packets_reordered, 0);
+    }
+
+    #[test]
+    fn duplicate_in_window() {
+        let stats = collect(&[0, 1, 2, 1]).stats(3);
+        assert_eq!(stats.packets_received, 3);
+        assert_eq!(stats.packets_duplicated, 1);
+        assert_eq!(stats.packets_reordered, 0);
+    }
+
+    #[test]
+    fn late_packet_in_window_is_reordered() {
+        let collector = collect(&[0, 2, 3, 1]);
+        let stats = collector.stats(4);
+        assert_eq!(stats.packets_received, 4);
+        assert_eq!(stats.packets_reordered, 1);
+        assert_eq!(stats.packets_duplicated, 0);
+        assert_eq!(stats.packets_lost, 0);
+    }
+
+    #[test]
+    fn packet_behind_the_window() {
+        let mut collector = collect(&[0, 1, UDP_REORDER_WINDOW + 1]);
+        // Too old to tell a duplicate apart; its slot belongs to a newer sequence number.
+        collector.record(1, 0, 0, 100);
+        collector.record(UDP_REORDER_WINDOW + 1, 0, 0, 100);
+        let stats = collector.stats(UDP_REORDER_WINDOW + 2);
+        assert_eq!(stats.packets_received, 4);
+        assert_eq!(stats.packets_reordered, 1);
+        assert_eq!(stats.packets_duplicated, 1);
+    }
+
+    #[test]
+    fn jump_of_more_than_a_window_clears_the_slots() {
+        let seq = 2 * UDP_REORDER_WINDOW + 5;
+        let mut collector = collect(&[0, 1, 2, 3, 4, seq]);
+        // seq - 1 shares its slot with 4, which was seen before the jump.
+        collector.record(seq - 1, 0, 0, 100);
+        let stats = collector.stats(seq + 1);
+        assert_eq!(stats.packets_received, 7);
+        assert_eq!(stats.packets_duplicated, 0);
+        assert_eq!(stats.packets_reordered, 1);
+    }
+
+    #[test]
+    fn loss_against_sent() {
+        let collector = collect(&[0, 2, 4]);
+        assert_eq!(collector.stats(5).packets_lost, 2);
+        // Never below zero, whatever the peer claims to have sent.
+        assert_eq!(collector.stats(2).packets_lost, 0);
+        assert_eq!(UdpStatsCollector::new().stats(3).packets_lost, 3);
+    }
+}
@@ -0,0 +1,210 @@
+use crate::Throughput;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnitBase {
+    Si,  // 1000
+    Iec, // 1024
+}
+
+impl UnitBase {
+    fn factor(self) -> f64 {
+        match self {
+            UnitBase::Si => 1000.,
+            UnitBase::Iec => 1024.,
+        }
+    }
+}
+
+impl FromStr for UnitBase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "si" | "SI" => Ok(UnitBase::Si),
+            "iec" | "IEC" => Ok(UnitBase::Iec),
+            _ => Err(format!("Unknown unit base: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum UnitPrefix {
+    One,
+    Kilo,
+    Mega,
+    Giga,
+    Tera,
+}
+
+impl UnitPrefix {
+    const ALL: [UnitPrefix; 5] = [
+        UnitPrefix::One,
+        UnitPrefix::Kilo,
+        UnitPrefix::Mega,
+        UnitPrefix::Giga,
+        UnitPrefix::Tera,
+    ];
+
+    fn exponent(self) -> i32 {
+        self as i32
+    }
+
+    fn symbol(self, base: UnitBase) -> &'static str {
+        match (self, base) {
+            (UnitPrefix::One, _) => "",
+            (UnitPrefix::Kilo, UnitBase::Si) => "K",
+            (UnitPrefix::Mega, UnitBase::Si) => "M",
+            (UnitPrefix::Giga, UnitBase::Si) => "G",
+            (UnitPrefix::Tera, UnitBase::Si) => "T",
+            (UnitPrefix::Kilo, UnitBase::Iec) => "Ki",
+            (UnitPrefix::Mega, UnitBase::Iec) => "Mi",
+            (UnitPrefix::Giga, UnitBase::Iec) => "Gi",
+            (UnitPrefix::Tera, UnitBase::Iec) => "Ti",
+        }
+    }
+
+    // The largest prefix that keeps the scaled magnitude at 1 or above. Values can be
+    // negative, e.g. the lower bound of a wide confidence interval.
+    fn fit(value: f64, base: UnitBase) -> Self {
+        Self::ALL
+            .into_iter()
+            .rev()
+            .find(|x| value.abs() >= base.factor().powi(x.exponent()))
+            .unwrap_or(UnitPrefix::One)
+    }
+}
+
+impl FromStr for UnitPrefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" => Ok(UnitPrefix::One),
+            "k" | "K" => Ok(UnitPrefix::Kilo),
+            "m" | "M" => Ok(UnitPrefix::Mega),
+            "g" | "G" => Ok(UnitPrefix::Giga),
+            "t" | "T" => Ok(UnitPrefix::Tera),
+            _ => Err(format!(
+                "Unknown unit prefix: {s} (expected 1, K, M, G or T)"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpeedFormat {
+    pub base: UnitBase,
+    pub bytes: bool, // bytes per second instead of bits per second
+    pub precision: usize,
+    pub unit: Option<UnitPrefix>, // None picks the prefix from the value
+}
+
+impl Default for SpeedFormat {
+    fn default() -> Self {
+        Self {
+            base: UnitBase::Si,
+            bytes: false,
+            precision: 2,
+            unit: None,
+        }
+    }
+}
+
+impl SpeedFormat {
+    pub fn format(&self, throughput: Throughput) -> String {
+        let (value, symbol) = if self.bytes {
+            (throughput.bytes_per_sec(), "B")
+        } else {
+            (throughput.bits_per_sec(), "b")
+        };
+        let prefix = self
+            .unit
+            .unwrap_or_else(|| UnitPrefix::fit(value, self.base));
+        let scaled = value / self.base.factor().powi(prefix.exponent());
+
+        format!(
+            "{scaled:.precision$} {}{symbol}/s",
+            prefix.symbol(self.base),
+            precision = self.precision
+        )
+    }
+}
+
+static SPEED_FORMAT: OnceLock<SpeedFormat> = OnceLock::new();
+
+// Sets the format of get_human_friendly_speed_str for the whole process. Only the
+// first call has an effect, so binaries call it once at startup.
+pub fn set_speed_format(format: SpeedFormat) {
+    let _ = SPEED_FORMAT.set(format);
+}
+
+pub fn speed_format() -> SpeedFormat {
+    SPEED_FORMAT.get().copied().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bits(bits_per_sec: f64) -> Throughput {
+        Throughput(bits_per_sec / 8.)
+    }
+
+    #[test]
+    fn keeps_the_fraction() {
+        let format = SpeedFormat::default();
+        assert_eq!(format.format(bits(1.9e9)), "1.90 Gb/s");
+        assert_eq!(format.format(bits(999.)), "999.00 b/s");
+        assert_eq!(format.format(bits(0.)), "0.00 b/s");
+    }
+
+    #[test]
+    fn si_and_iec_prefixes() {
+        let iec = SpeedFormat {
+            base: UnitBase::Iec,
+            ..Default::default()
+        };
+        assert_eq!(iec.format(bits(1.5 * (1u64 << 30) as f64)), "1.50 Gib/s");
+        assert_eq!(iec.format(bits(1000.)), "1000.00 b/s");
+        assert_eq!(SpeedFormat::default().format(bits(1000.)), "1.00 Kb/s");
+    }
+
+    #[test]
+    fn bytes_instead_of_bits() {
+        let format = SpeedFormat {
+            bytes: true,
+            ..Default::default()
+        };
+        assert_eq!(format.format(Throughput(2.5e6)), "2.50 MB/s");
+    }
+
+    #[test]
+    fn precision() {
+        let format = |precision| SpeedFormat {
+            precision,
+            ..Default::default()
+        };
+        assert_eq!(format(0).format(bits(1.9e9)), "2 Gb/s");
+        assert_eq!(format(4).format(bits(1.9e9)), "1.9000 Gb/s");
+    }
+
+    #[test]
+    fn forced_prefix() {
+        let format = |unit| SpeedFormat {
+            unit: Some(unit),
+            ..Default::default()
+        };
+        assert_eq!(format(UnitPrefix::Mega).format(bits(1.9e9)), "1900.00 Mb/s");
+        assert_eq!(format(UnitPrefix::Giga).format(bits(1e6)), "0.00 Gb/s");
+        assert_eq!(format(UnitPrefix::One).format(bits(1500.)), "1500.00 b/s");
+    }
+
+    #[test]
+    fn negative_values_fit_by_magnitude() {
+        let format = SpeedFormat::default();
+        assert_eq!(format.format(bits(-1.9e9)), "-1.90 Gb/s");
+        assert_eq!(format.format(bits(-500.)), "-500.00 b/s");
+    }
+}
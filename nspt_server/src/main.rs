@@ -1,12 +1,27 @@
-use log::{info, trace};
+use log::{error, info, trace, warn};
 #[cfg(not(target_os = "windows"))]
 use nspt_common::DEFAULT_SOCK_FILE;
 use nspt_common::{
-    get_human_friendly_data_size_str, Listener, NsptNegProtocol, ReadWriteStream,
-    SerializedDataContainer, TestMode, BUF_SIZE, PROTOCOL_VER, SERVER_PORT_S, TOTAL_SEND_NEG_BYTES,
+    fill_random_bytes, new_session_cookie, parse_size, read_udp_header, recv_data, recv_expected,
+    recv_hello, report_error, run_timed_round, send_data, send_greeting, set_max_frame_size,
+    set_speed_format, Capabilities, ControlStream, Hello, LatencyTestParams, Listener, MessageKind,
+    Negotiated, NsptError, NsptNegProtocol, ReadWriteStream, RoundLimit, SessionCookie,
+    SpeedFormat, StreamMeters, TestDirection, TestMode, ThroughputReport, TransferReport,
+    UdpStatsCollector, UdpTestParams, UnitBase, UnitPrefix, BUF_SIZE, CONTROL_TIMEOUT,
+    MAX_LATENCY_MESSAGE_SIZE, MAX_PARALLEL_STREAMS, MAX_ROUND_DURATION, MAX_UDP_PACKET_SIZE,
+    MIN_MAX_FRAME_SIZE, PROTOCOL_VER, ROUND_POLL_INTERVAL, SERVER_PORT_S, TOTAL_SEND_NEG_BYTES,
+    UDP_GRACE_PERIOD, UDP_HEADER_SIZE,
 };
+use std::collections::HashMap;
 use std::env;
-use std::net::{TcpListener, TcpStream};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
 #[cfg(not(target_os = "windows"))]
 use std::{fs, os::unix::net::UnixListener, path::Path};
 use structopt::StructOpt;
@@ -23,167 +38,596 @@ fn packet_peeker(stream: TcpStream) {
     }
 }
 
-fn do_test(
-    mut client_stream: &mut Box<dyn ReadWriteStream + Send>,
+type DataStreamSender = Sender<Box<dyn ReadWriteStream + Send>>;
+
+struct ServerState {
+    sessions: Mutex<HashMap<SessionCookie, DataStreamSender>>,
+    active_clients: AtomicUsize,
+    max_clients: usize,
+    pending: AtomicUsize,
+    max_pending: usize,
+    capabilities: Capabilities,
+}
+
+impl ServerState {
+    fn new(max_clients: usize, max_pending: usize, capabilities: Capabilities) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            active_clients: AtomicUsize::new(0),
+            max_clients,
+            pending: AtomicUsize::new(0),
+            max_pending,
+            capabilities,
+        }
+    }
+
+    fn admit(self: &Arc<Self>) -> Option<PendingConnection> {
+        self.pending
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < self.max_pending).then_some(n + 1)
+            })
+            .ok()?;
+
+        Some(PendingConnection {
+            state: Arc::clone(self),
+        })
+    }
+
+    fn open_session(&self) -> Option<Session<'_>> {
+        self.active_clients
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < self.max_clients).then_some(n + 1)
+            })
+            .ok()?;
+
+        let (sender, data_streams) = channel();
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut cookie = new_session_cookie();
+        while sessions.contains_key(&cookie) {
+            cookie = new_session_cookie();
+        }
+        sessions.insert(cookie, sender);
+
+        Some(Session {
+            state: self,
+            cookie,
+            data_streams,
+        })
+    }
+
+    fn attach_data_stream(
+        &self,
+        cookie: SessionCookie,
+        stream: Box<dyn ReadWriteStream + Send>,
+    ) -> bool {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(&cookie)
+            .is_some_and(|sender| sender.send(stream).is_ok())
+    }
+}
+
+// A connection whose hello has not arrived yet. Each one holds a thread for up to
+// CONTROL_TIMEOUT, so only `max_pending` of them are accepted at a time.
+struct PendingConnection {
+    state: Arc<ServerState>,
+}
+
+impl Drop for PendingConnection {
+    fn drop(&mut self) {
+        self.state.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct Session<'a> {
+    state: &'a ServerState,
+    cookie: SessionCookie,
+    data_streams: Receiver<Box<dyn ReadWriteStream + Send>>,
+}
+
+impl Session<'_> {
+    fn accept_data_stream(&self) -> Result<Box<dyn ReadWriteStream + Send>, NsptError> {
+        self.data_streams
+            .recv_timeout(CONTROL_TIMEOUT)
+            .map_err(|_| NsptError::Timeout)
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        self.state.sessions.lock().unwrap().remove(&self.cookie);
+        self.state.active_clients.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn serve_speed_test(
     test_stream: &mut Box<dyn ReadWriteStream + Send>,
-    client_addr: String,
-) {
-    info!("New client({client_addr}) connected!");
+    transfer_size: usize,
+    direction: TestDirection,
+) -> Result<ThroughputReport, NsptError> {
+    let mut buf: [u8; BUF_SIZE] = [0; BUF_SIZE];
+    if direction != TestDirection::Upload {
+        fill_random_bytes(&mut buf);
+    }
+    let transfer = |start: Instant| TransferReport::new(transfer_size as u64, start.elapsed());
 
-    {
+    let throughput = match direction {
+        TestDirection::Upload => {
+            let elapsed = recv_data(test_stream, &mut buf, transfer_size)?;
+            ThroughputReport {
+                upload: Some(TransferReport::new(transfer_size as u64, elapsed)),
+                ..Default::default()
+            }
+        }
+        TestDirection::Download => {
+            let start = Instant::now();
+            send_data(test_stream, &buf, transfer_size)?;
+            ThroughputReport {
+                download: Some(transfer(start)),
+                ..Default::default()
+            }
+        }
+        TestDirection::Bidirectional => {
+            let mut recv_stream = test_stream.try_clone()?;
+            let (sent, received) = thread::scope(|s| {
+                let receiver = s.spawn(move || {
+                    let mut recv_buf = [0; BUF_SIZE];
+                    recv_data(&mut recv_stream, &mut recv_buf, transfer_size)
+                        .map(|elapsed| TransferReport::new(transfer_size as u64, elapsed))
+                });
+                let start = Instant::now();
+                let sent = send_data(test_stream, &buf, transfer_size).map(|_| transfer(start));
+                (sent, receiver.join().expect("Receiver thread panicked"))
+            });
+            ThroughputReport {
+                upload: Some(received?),
+                download: Some(sent?),
+            }
+        }
+    };
+
+    Ok(throughput)
+}
+
+fn do_test(
+    client_stream: &mut ControlStream,
+    client_hello: &Hello,
+    session: &Session,
+    client_ip: Option<IpAddr>,
+) -> Result<(), NsptError> {
+    let negotiated = {
         // Exchange Hello Message - Negotiation
-        client_stream
-            .write_all(
-                &SerializedDataContainer::from_serializable_data(&NsptNegProtocol::ServerHello(
-                    PROTOCOL_VER,
-                ))
-                .unwrap()
-                .to_one_vec(),
-            )
-            .expect("Failed to send ServerHello");
-
-        let proto_ver_matched = if let NsptNegProtocol::ClientHello(client_proto_ver) =
-            SerializedDataContainer::from_reader(&mut client_stream)
-                .unwrap()
-                .to_serializable_data()
-                .expect("Unexpected data received, expected ClientHello")
-        {
-            client_proto_ver == PROTOCOL_VER
-        } else {
-            panic!("Protocol Err");
-        };
+        // The reply goes out even without a common version, so the client can tell why.
+        let local = Hello::new(session.state.capabilities);
+        client_stream.send(&NsptNegProtocol::ServerHello(local, session.cookie))?;
+
+        local.negotiate(client_hello)?
+    };
+    info!(
+        "proto-ver: {:#04x}, capabilities: {}",
+        negotiated.version, negotiated.capabilities
+    );
 
-        if !proto_ver_matched {
-            info!("Negotiation failed... reset connection.");
-            return;
+    match client_stream.recv()? {
+        NsptNegProtocol::NotifyStreamCount(stream_count) => {
+            do_stream_test(client_stream, session, &negotiated, stream_count)
+        }
+        NsptNegProtocol::NotifyUdpTest(params) => {
+            negotiated.require(Capabilities::UDP)?;
+            do_udp_test(client_stream, params, client_ip)
+        }
+        NsptNegProtocol::NotifyLatencyTest(params) => {
+            negotiated.require(Capabilities::LATENCY)?;
+            do_latency_test(client_stream, session, params)
         }
+        other => Err(NsptError::unexpected(MessageKind::NotifyStreamCount, other)),
     }
+}
+
+fn do_latency_test(
+    client_stream: &mut ControlStream,
+    session: &Session,
+    params: LatencyTestParams,
+) -> Result<(), NsptError> {
+    if params.message_size == 0 || params.message_size > MAX_LATENCY_MESSAGE_SIZE {
+        return Err(NsptError::InvalidParameter(format!(
+            "message size {} is out of range (1..={MAX_LATENCY_MESSAGE_SIZE})",
+            params.message_size
+        )));
+    }
+
+    info!(
+        "Latency test - message_size: {}, transactions: {}",
+        params.message_size, params.transactions
+    );
+
+    let mut test_stream = session.accept_data_stream()?;
+    test_stream.set_nodelay(true)?;
+
+    client_stream.send(&NsptNegProtocol::StartSpeedTest)?;
+
+    let mut buf = vec![0; params.message_size];
+    for _ in 0..params.transactions {
+        test_stream.read_exact(&mut buf)?;
+        test_stream.write_all(&buf)?;
+    }
+
+    client_stream.expect(MessageKind::EndOfTransfer)?;
+
+    client_stream.send(&NsptNegProtocol::EndOfSpeedTest)?;
+
+    Ok(())
+}
+
+// Stops the UDP receiver on every way out of the test, so it never outlives the session.
+struct StopOnDrop<'a>(&'a AtomicBool);
+
+impl Drop for StopOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+fn do_udp_test(
+    client_stream: &mut ControlStream,
+    params: UdpTestParams,
+    client_ip: Option<IpAddr>,
+) -> Result<(), NsptError> {
+    if params.packet_size < UDP_HEADER_SIZE || params.packet_size > MAX_UDP_PACKET_SIZE {
+        return Err(NsptError::InvalidParameter(format!(
+            "UDP packet size {} is out of range ({UDP_HEADER_SIZE}..={MAX_UDP_PACKET_SIZE})",
+            params.packet_size
+        )));
+    }
+    if params.round_duration.is_zero() || params.round_duration > MAX_ROUND_DURATION {
+        return Err(NsptError::InvalidParameter(format!(
+            "UDP round duration {:?} is out of range (up to {MAX_ROUND_DURATION:?})",
+            params.round_duration
+        )));
+    }
+    // The whole test runs while we wait for the end of it on the control connection.
+    let timeout = params
+        .round_duration
+        .checked_mul(params.test_times as u32)
+        .and_then(|test_duration| CONTROL_TIMEOUT.checked_add(test_duration))
+        .ok_or_else(|| {
+            NsptError::InvalidParameter(format!(
+                "UDP test of {} rounds of {:?} is too long",
+                params.test_times, params.round_duration
+            ))
+        })?;
+    let client_ip = client_ip.ok_or_else(|| {
+        NsptError::InvalidParameter("UDP test needs a TCP control connection".to_string())
+    })?;
+
+    info!(
+        "UDP test - bitrate: {} b/s, packet_size: {}, test_times: {}",
+        params.bitrate, params.packet_size, params.test_times
+    );
+
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_read_timeout(Some(UDP_GRACE_PERIOD))?;
+    client_stream.send(&NsptNegProtocol::NotifyUdpPort(socket.local_addr()?.port()))?;
+
+    let stop = AtomicBool::new(false);
+    let stats = thread::scope(|s| {
+        let stop_receiver = StopOnDrop(&stop);
+        let receiver = s.spawn(|| -> Result<UdpStatsCollector, NsptError> {
+            let mut collector = UdpStatsCollector::new();
+            let mut buf = vec![0; params.packet_size];
+            let mut connected = false;
+            let start = Instant::now();
+
+            while !stop.load(Ordering::SeqCst) {
+                let (len, src) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                        continue
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                // Anyone can send to the port until the first datagram of the client
+                // arrives; after that the socket only takes datagrams from its address.
+                if src.ip() != client_ip {
+                    continue;
+                }
+                if !connected {
+                    socket.connect(src)?;
+                    connected = true;
+                }
+
+                if let Some((seq, sent_ns)) = read_udp_header(&buf[..len]) {
+                    collector.record(seq, sent_ns, start.elapsed().as_nanos() as u64, len);
+                }
+            }
+
+            Ok(collector)
+        });
+
+        let packets_sent = client_stream
+            .set_read_timeout(Some(timeout))
+            .map_err(NsptError::from)
+            .and_then(|_| {
+                Ok(recv_expected!(
+                    client_stream,
+                    EndOfUdpTransfer(packets_sent) => packets_sent
+                ))
+            });
+
+        thread::sleep(UDP_GRACE_PERIOD);
+        drop(stop_receiver);
+        let collector = receiver.join().expect("Receiver thread panicked");
+
+        // Datagrams lost at the end of the test only show up against the sent count.
+        packets_sent.and_then(|sent| collector.map(|collector| collector.stats(sent)))
+    })?;
+    client_stream.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+
+    info!(
+        "UDP result - received: {}, lost: {}, duplicated: {}, out-of-order: {}, jitter: {} ns",
+        stats.packets_received,
+        stats.packets_lost,
+        stats.packets_duplicated,
+        stats.packets_reordered,
+        stats.jitter_ns
+    );
+
+    client_stream.send(&NsptNegProtocol::UdpTestReport(stats))?;
+    client_stream.send(&NsptNegProtocol::EndOfSpeedTest)?;
+
+    Ok(())
+}
+
+fn do_stream_test(
+    client_stream: &mut ControlStream,
+    session: &Session,
+    negotiated: &Negotiated,
+    stream_count: u16,
+) -> Result<(), NsptError> {
+    if stream_count == 0 || stream_count > MAX_PARALLEL_STREAMS {
+        return Err(NsptError::InvalidParameter(format!(
+            "stream count {stream_count} is out of range (1..={MAX_PARALLEL_STREAMS})"
+        )));
+    }
+    if stream_count > 1 {
+        negotiated.require(Capabilities::PARALLEL)?;
+    }
+
+    let mut test_streams = Vec::with_capacity(stream_count as usize);
+    for _ in 0..stream_count {
+        test_streams.push(session.accept_data_stream()?);
+    }
+    info!("streams: {stream_count}");
+
+    let direction = recv_expected!(client_stream, NotifyTestDirection(direction) => direction);
+    match direction {
+        TestDirection::Upload => {}
+        TestDirection::Download => negotiated.require(Capabilities::DOWNLOAD)?,
+        TestDirection::Bidirectional => negotiated.require(Capabilities::BIDIRECTIONAL)?,
+    }
+    info!("direction: {direction}");
 
     {
         // Determine transfer buffer size
+        let is_required =
+            recv_expected!(client_stream, SpeedNegotiation(is_required) => is_required);
 
-        if let NsptNegProtocol::SpeedNegotiation(is_required) =
-            SerializedDataContainer::from_reader(&mut client_stream)
-                .unwrap()
-                .to_serializable_data::<NsptNegProtocol>()
-                .expect("Unexpected data received, expected SpeedNegotiation")
-        {
-            if is_required {
-                if let NsptNegProtocol::StartSpeedNegotiation =
-                    SerializedDataContainer::from_reader(&mut client_stream)
-                        .unwrap()
-                        .to_serializable_data::<NsptNegProtocol>()
-                        .expect("Unexpected data received, expected StartSpeedNegotiation")
-                {
-                    client_stream
-                        .write_all(
-                            &SerializedDataContainer::from_serializable_data(
-                                &NsptNegProtocol::StartSpeedNegotiation,
-                            )
-                            .unwrap()
-                            .to_one_vec(),
-                        )
-                        .expect("Failed to send StartSpeedNegotiation");
-
-                    let mut neg_test_buf: [u8; BUF_SIZE] = [0; BUF_SIZE];
-                    let mut total: usize = 0;
-
-                    info!("Start to determin unit size of test.");
-
-                    while total < TOTAL_SEND_NEG_BYTES {
-                        client_stream.read_exact(&mut neg_test_buf).unwrap();
-                        total += BUF_SIZE;
-                    }
+        if is_required {
+            client_stream.expect(MessageKind::StartSpeedNegotiation)?;
+
+            client_stream.send(&NsptNegProtocol::StartSpeedNegotiation)?;
+
+            let test_stream = &mut test_streams[0];
+            let mut neg_test_buf: [u8; BUF_SIZE] = [0; BUF_SIZE];
+            let mut total: usize = 0;
+
+            info!("Start to determin unit size of test.");
+
+            if direction == TestDirection::Download {
+                fill_random_bytes(&mut neg_test_buf);
+            }
 
-                    info!("End determining unit size of test.");
-                } else {
-                    panic!("Protocol err")
+            while total < TOTAL_SEND_NEG_BYTES {
+                match direction {
+                    TestDirection::Download => test_stream.write_all(&neg_test_buf)?,
+                    _ => test_stream.read_exact(&mut neg_test_buf)?,
                 }
+                total += BUF_SIZE;
             }
-        } else {
-            panic!("Protocol err")
+
+            info!("End determining unit size of test.");
         }
     }
 
-    // Receive transfer size from client
-    let (transfer_size, test_times) = {
-        if let NsptNegProtocol::NotifyBufferSize(transfer_size, test_times) =
-            SerializedDataContainer::from_reader(&mut client_stream)
-                .unwrap()
-                .to_serializable_data::<NsptNegProtocol>()
-                .expect("Unexpected data received, expected NotifyBufferSize")
-        {
-            info!(
-                "transfer_size: {}, test_times: {test_times}",
-                get_human_friendly_data_size_str(transfer_size as u64)
-            );
+    // Receive transfer size (or duration) of a round from client
+    let (limit, test_times) = match client_stream.recv()? {
+        NsptNegProtocol::NotifyBufferSize(transfer_size, test_times) => {
+            (RoundLimit::Bytes(transfer_size), test_times)
+        }
+        NsptNegProtocol::NotifyRoundDuration(duration, test_times) => {
+            negotiated.require(Capabilities::TIMED_ROUNDS)?;
+            if duration.is_zero() || duration > MAX_ROUND_DURATION {
+                return Err(NsptError::InvalidParameter(format!(
+                    "round duration {duration:?} is out of range (up to {MAX_ROUND_DURATION:?})"
+                )));
+            }
+            // Rounds are ended by EndOfRound, which arrives only after the duration.
+            let timeout = CONTROL_TIMEOUT.checked_add(duration).ok_or_else(|| {
+                NsptError::InvalidParameter(format!("round duration {duration:?} is too long"))
+            })?;
+            client_stream.set_read_timeout(Some(timeout))?;
+            for test_stream in &test_streams {
+                test_stream.set_read_timeout(Some(ROUND_POLL_INTERVAL))?;
+            }
 
-            (transfer_size, test_times)
-        } else {
-            panic!("Protocol err")
+            (RoundLimit::Duration(duration), test_times)
         }
+        other => return Err(NsptError::unexpected(MessageKind::NotifyBufferSize, other)),
     };
+    match test_times {
+        Some(0) => {
+            return Err(NsptError::InvalidParameter(
+                "test times must be greater than 0".to_string(),
+            ))
+        }
+        Some(test_times) => info!("round: {limit}, test_times: {test_times}"),
+        None => {
+            negotiated.require(Capabilities::OPEN_ENDED_ROUNDS)?;
+            info!("round: {limit}, test_times: open-ended");
+        }
+    }
+    let open_ended = test_times.is_none();
+    let round_report = negotiated.capabilities.contains(Capabilities::ROUND_REPORT);
+
+    // In timed mode the client starts a round only after it has drained the previous one.
+    let announced = open_ended || matches!(limit, RoundLimit::Duration(_));
+    let mut transfer_ended = false;
 
     {
         // Speed Test Main
-        client_stream
-            .write_all(
-                &SerializedDataContainer::from_serializable_data(&NsptNegProtocol::StartSpeedTest)
-                    .unwrap()
-                    .to_one_vec(),
-            )
-            .expect("Failed to send StartSpeedTest");
+        client_stream.send(&NsptNegProtocol::StartSpeedTest)?;
 
-        let mut buf: [u8; BUF_SIZE] = [0; BUF_SIZE];
+        let mut round: u32 = 0;
+        while test_times.is_none_or(|test_times| round < test_times as u32) {
+            if announced {
+                match client_stream.recv()? {
+                    NsptNegProtocol::StartRound => {}
+                    NsptNegProtocol::EndOfTransfer => {
+                        transfer_ended = true;
+                        break;
+                    }
+                    other => return Err(NsptError::unexpected(MessageKind::StartRound, other)),
+                }
+            }
+            round += 1;
+
+            info!("Start transsfer data unit for speed testing - round {round}");
+
+            let throughputs = match limit {
+                RoundLimit::Bytes(transfer_size) => thread::scope(|s| {
+                    let handles: Vec<_> = test_streams
+                        .iter_mut()
+                        .map(|test_stream| {
+                            s.spawn(move || serve_speed_test(test_stream, transfer_size, direction))
+                        })
+                        .collect();
+
+                    handles
+                        .into_iter()
+                        .map(|handle| handle.join().expect("Stream thread panicked"))
+                        .collect::<Result<Vec<_>, _>>()
+                })?,
+                RoundLimit::Duration(duration) => {
+                    // The server does not report intervals, so its meters record nothing.
+                    let mut meters: Vec<_> = test_streams
+                        .iter()
+                        .map(|_| StreamMeters::new(Instant::now(), None))
+                        .collect();
+                    run_timed_round(
+                        client_stream,
+                        &mut test_streams,
+                        duration,
+                        direction,
+                        &mut meters,
+                    )?
+                }
+            };
 
-        for round in 0..test_times {
             info!(
-                "Start transsfer data unit for speed testing - round {}",
-                round + 1
+                "Finish Data Unit Transfer - speed: {}",
+                ThroughputReport::sum(&throughputs).to_speed_str(direction)
             );
+            if round_report {
+                client_stream.send(&NsptNegProtocol::NotifyRoundReport(throughputs))?;
+            }
+        }
+    }
 
-            let mut next_read_size = BUF_SIZE;
-            let mut remain = transfer_size;
+    {
+        // End of Test.
+        if !transfer_ended {
+            client_stream.expect(MessageKind::EndOfTransfer)?;
+        }
 
-            while remain > 0 {
-                let r = test_stream.read_exact(&mut buf[..next_read_size]);
+        client_stream.send(&NsptNegProtocol::EndOfSpeedTest)?;
+    }
 
-                if r.is_err() {
-                    info!("Connection is closed unexpectely");
-                    return;
-                }
+    Ok(())
+}
 
-                remain -= next_read_size;
-                next_read_size = BUF_SIZE;
-                if remain < next_read_size {
-                    next_read_size = remain;
-                }
-                // println!("transfer_size: {transfer_size}, next_read_size: {next_read_size}, remain: {remain}, BUF_SIZE: {BUF_SIZE}");
+fn handle_connection(
+    mut stream: Box<dyn ReadWriteStream + Send>,
+    peer_addr: String,
+    pending: PendingConnection,
+) {
+    let hello = stream
+        .set_read_timeout(Some(CONTROL_TIMEOUT))
+        .map_err(NsptError::from)
+        .and_then(|_| send_greeting(&mut stream))
+        .and_then(|_| recv_hello(&mut stream));
+    let state = Arc::clone(&pending.state);
+    drop(pending);
+
+    match hello {
+        Ok(NsptNegProtocol::ClientHello(client_hello, None)) => {
+            handle_client(stream, peer_addr, &client_hello, &state)
+        }
+        Ok(NsptNegProtocol::ClientHello(_, Some(cookie))) => {
+            if state.attach_data_stream(cookie, stream) {
+                info!("Data connection({peer_addr:?}) attached to session {cookie:#018x}.");
+            } else {
+                warn!("Data connection({peer_addr:?}) has unknown session {cookie:#018x}.");
             }
+        }
+        Ok(other) => {
+            let e = NsptError::unexpected(MessageKind::ClientHello, other);
+            error!("Connection({peer_addr:?}) failed: {e}");
+            report_error(&mut stream, &e);
+        }
+        Err(e) => error!("Connection({peer_addr:?}) failed: {e}"),
+    }
+}
+
+fn handle_client(
+    stream: Box<dyn ReadWriteStream + Send>,
+    client_addr: String,
+    client_hello: &Hello,
+    state: &ServerState,
+) {
+    let mut client_stream = match ControlStream::accept(stream, client_hello) {
+        Ok(client_stream) => client_stream,
+        Err(e) => return error!("Connection({client_addr:?}) failed: {e}"),
+    };
 
-            info!("Finish Data Unit Transfer");
+    // A version 1 client got our version from the greeting and has already given up.
+    if client_hello.is_legacy() {
+        if let Err(e) = Hello::new(state.capabilities).negotiate(client_hello) {
+            error!("Connection({client_addr:?}) failed: {e}");
         }
+        return;
     }
 
-    {
-        // End of Test.
-        if let NsptNegProtocol::EndOfTransfer =
-            SerializedDataContainer::from_reader(&mut client_stream)
-                .unwrap()
-                .to_serializable_data::<NsptNegProtocol>()
-                .expect("Unexpected data received, expected EndOfTransfer")
-        {
-        } else {
-            panic!("Protocol err")
+    let Some(session) = state.open_session() else {
+        warn!("Server is busy, reject client({client_addr:?}).");
+        if let Err(e) = client_stream.send(&NsptNegProtocol::ServerBusy) {
+            error!("Failed to send ServerBusy to client({client_addr:?}): {e}");
         }
+        return;
+    };
 
-        client_stream
-            .write_all(
-                &SerializedDataContainer::from_serializable_data(&NsptNegProtocol::EndOfSpeedTest)
-                    .unwrap()
-                    .to_one_vec(),
-            )
-            .expect("Failed to send EndOfSpeedTest");
+    info!(
+        "New client({client_addr:?}) connected! session: {:#018x}",
+        session.cookie
+    );
+
+    let client_ip = client_addr.parse::<SocketAddr>().ok().map(|x| x.ip());
+    match do_test(&mut client_stream, client_hello, &session, client_ip) {
+        Ok(()) => info!("Test with client({client_addr:?}) finished."),
+        Err(e) => {
+            error!("Test with client({client_addr:?}) failed: {e}");
+            client_stream.report_error(&e);
+        }
     }
 }
 
@@ -197,6 +641,30 @@ struct NsptServerArg {
     #[cfg(not(target_os = "windows"))]
     #[structopt(short = "s", long, default_value = DEFAULT_SOCK_FILE)]
     server_sock: String,
+    #[structopt(short = "c", long, default_value = "8")]
+    max_clients: usize,
+    /// Connections that may wait for their hello at the same time
+    #[structopt(long, default_value = "64")]
+    max_pending: usize,
+    /// Features to refuse, comma separated (download, bidirectional, parallel, udp, latency,
+    /// timed-rounds, open-ended-rounds, round-report)
+    #[structopt(long, default_value = "", parse(try_from_str))]
+    disable: Capabilities,
+    /// Largest control message accepted from a client (e.g. 64K, 1M)
+    #[structopt(long, default_value = "1M", parse(try_from_str = parse_size))]
+    max_frame_size: usize,
+    /// Speed unit base: si (1000) or iec (1024)
+    #[structopt(long, default_value = "si", parse(try_from_str))]
+    units: UnitBase,
+    /// Report speeds in bytes/sec instead of bits/sec
+    #[structopt(long)]
+    bytes: bool,
+    /// Number of decimal places in reported speeds
+    #[structopt(long, default_value = "2")]
+    precision: usize,
+    /// Always report speeds with this unit prefix (1, K, M, G or T)
+    #[structopt(long, parse(try_from_str))]
+    format_unit: Option<UnitPrefix>,
 }
 
 fn main() {
@@ -204,40 +672,77 @@ fn main() {
     env_logger::init();
 
     let nspt_server_args = NsptServerArg::from_args();
+    if nspt_server_args.max_frame_size < MIN_MAX_FRAME_SIZE {
+        eprintln!("Max frame size must be at least {MIN_MAX_FRAME_SIZE} bytes");
+        process::exit(1);
+    }
+    set_max_frame_size(nspt_server_args.max_frame_size);
 
-    let (listner, server_addr): (Box<dyn Listener>, String) = match nspt_server_args.test_mode {
-        TestMode::Tcp => {
-            let addr = format!("0.0.0.0:{}", nspt_server_args.server_port);
-            (Box::new(TcpListener::bind(&addr).unwrap()), addr)
-        }
-        #[cfg(not(target_os = "windows"))]
-        TestMode::Unix => {
-            let sockfile = Path::new(&nspt_server_args.server_sock);
-            if sockfile.exists() {
-                fs::remove_file(sockfile).unwrap();
+    if nspt_server_args.max_clients == 0 {
+        eprintln!("Max clients must be greater than 0");
+        process::exit(1);
+    }
+
+    if nspt_server_args.max_pending == 0 {
+        eprintln!("Max pending connections must be greater than 0");
+        process::exit(1);
+    }
+
+    set_speed_format(SpeedFormat {
+        base: nspt_server_args.units,
+        bytes: nspt_server_args.bytes,
+        precision: nspt_server_args.precision,
+        unit: nspt_server_args.format_unit,
+    });
+
+    let (listner, server_addr): (Box<dyn Listener<'static>>, String) =
+        match nspt_server_args.test_mode {
+            TestMode::Tcp | TestMode::Udp => {
+                let addr = format!("0.0.0.0:{}", nspt_server_args.server_port);
+                (Box::new(TcpListener::bind(&addr).unwrap()), addr)
             }
-            (
-                Box::new(UnixListener::bind(sockfile).unwrap()),
-                nspt_server_args.server_sock,
-            )
-        }
-    };
+            #[cfg(not(target_os = "windows"))]
+            TestMode::Unix => {
+                let sockfile = Path::new(&nspt_server_args.server_sock);
+                if sockfile.exists() {
+                    fs::remove_file(sockfile).unwrap();
+                }
+                (
+                    Box::new(UnixListener::bind(sockfile).unwrap()),
+                    nspt_server_args.server_sock,
+                )
+            }
+        };
+
+    info!(" *** Server is ready for to be connected *** ");
+    info!(
+        "Test Mode: {:?}, Protocol Version: {PROTOCOL_VER:#04x}, Max Clients: {}",
+        nspt_server_args.test_mode, nspt_server_args.max_clients
+    );
+
+    let capabilities = Capabilities::ALL.difference(nspt_server_args.disable);
+    info!("Capabilities: {capabilities}");
+    let state = Arc::new(ServerState::new(
+        nspt_server_args.max_clients,
+        nspt_server_args.max_pending,
+        capabilities,
+    ));
 
     loop {
-        info!(" *** Server is ready for to be connected *** ");
-        info!(
-            "Test Mode: {:?}, Protocol Version: {PROTOCOL_VER:#04x}",
-            nspt_server_args.test_mode
-        );
         info!("Waiting a connection from client with {server_addr}");
-        let (mut client_stream, client_addr) = listner.accept().unwrap();
-
-        let mut test_stream = client_stream.try_clone().unwrap();
+        let (stream, peer_addr) = match listner.accept() {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept a connection: {e}");
+                continue;
+            }
+        };
 
-        do_test(
-            &mut client_stream,
-            &mut test_stream,
-            format!("{client_addr:?}"),
-        );
+        // Dropping the stream closes it, so a flood of idle sockets cannot pile up threads.
+        let Some(pending) = state.admit() else {
+            warn!("Too many pending connections, drop connection({peer_addr:?}).");
+            continue;
+        };
+        thread::spawn(move || handle_connection(stream, peer_addr, pending));
     }
 }